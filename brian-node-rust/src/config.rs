@@ -0,0 +1,346 @@
+//! Configuration of the flows, loaded from a TOML file.
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use robotica_node_rust::filters::reminder::{QuietHours, ReminderPolicy};
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::flows::robotica::Id;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub messages: Messages,
+    #[serde(default)]
+    pub lights: Vec<Spanned<Light>>,
+    #[serde(default)]
+    pub devices: Vec<Spanned<Device>>,
+    #[serde(default)]
+    pub door_sensors: Vec<Spanned<DoorSensor>>,
+    #[serde(default)]
+    pub reminders: Reminders,
+}
+
+/// Where to announce messages.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Messages {
+    pub locations: Vec<Spanned<String>>,
+}
+
+/// Limits on repeated reminders.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Reminders {
+    /// Stop repeating a reminder after this many repeats.
+    pub max_repeats: Option<usize>,
+    /// Do not send reminders during these hours.
+    pub quiet_hours: Option<Spanned<QuietHoursConfig>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    /// Local time in `HH:MM` format.
+    pub start: String,
+    /// Local time in `HH:MM` format.
+    pub end: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    "Australia/Melbourne".to_string()
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|err| anyhow!("invalid time {time}: {err}"))
+}
+
+impl QuietHoursConfig {
+    fn parse(&self) -> Result<QuietHours> {
+        Ok(QuietHours {
+            start: parse_time(&self.start)?,
+            end: parse_time(&self.end)?,
+            timezone: self
+                .timezone
+                .parse::<Tz>()
+                .map_err(|err| anyhow!("invalid timezone {}: {err}", self.timezone))?,
+        })
+    }
+}
+
+impl Reminders {
+    /// Get the policy for a reminder repeated at `intervals`.
+    pub fn policy(&self, intervals: Vec<Duration>) -> ReminderPolicy {
+        let quiet_hours = self.quiet_hours.as_ref().map(|quiet_hours| {
+            quiet_hours
+                .get_ref()
+                .parse()
+                .expect("quiet hours should have been validated")
+        });
+        ReminderPolicy {
+            intervals,
+            max_repeats: self.max_repeats,
+            quiet_hours,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Light {
+    pub location: String,
+    #[serde(default = "default_scene")]
+    pub scene: String,
+    /// Dim the light in the evening and early morning.
+    #[serde(default)]
+    pub dim_at_night: bool,
+    /// Only turn the light on automatically when someone is in the room.
+    pub presence: Option<Spanned<Presence>>,
+}
+
+fn default_scene() -> String {
+    "default".to_string()
+}
+
+impl Light {
+    pub fn id(&self) -> Id {
+        Id::new(&self.location, "Light")
+    }
+}
+
+/// An espresense room, and the distance at which someone is considered in the room.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Presence {
+    pub room: String,
+    pub threshold: f32,
+    /// The distance at which someone is considered to have left, defaults to `threshold`.
+    pub off_threshold: Option<f32>,
+}
+
+impl Presence {
+    pub fn off_threshold(&self) -> f32 {
+        self.off_threshold.unwrap_or(self.threshold)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    pub location: String,
+    pub device: String,
+}
+
+impl Device {
+    pub fn id(&self) -> Id {
+        Id::new(&self.location, &self.device)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DoorSensor {
+    /// Remind everyone to close the door if it is left open.
+    Reminder {
+        topic: String,
+        name: String,
+        /// Seconds the door must be open before the first reminder.
+        delay: u64,
+        /// Seconds between reminders, or a list to wait longer between each one.
+        repeat: Repeat,
+        /// Announce if nothing is heard from the sensor for this many seconds.
+        quiet_after: Option<u64>,
+    },
+    /// Announce if the bathroom is free, to everyone who asked.
+    Bathroom {
+        topic: String,
+        /// Light that shows when the bathroom is occupied.
+        alert_light: String,
+        /// Locations that can ask to be told when the bathroom is free.
+        locations: Vec<String>,
+        /// Announce if nothing is heard from the sensor for this many seconds.
+        quiet_after: Option<u64>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Repeat {
+    Every(u64),
+    Escalate(Vec<u64>),
+}
+
+impl Repeat {
+    pub fn intervals(&self) -> Vec<Duration> {
+        match self {
+            Repeat::Every(secs) => vec![Duration::from_secs(*secs)],
+            Repeat::Escalate(secs) => secs.iter().map(|s| Duration::from_secs(*s)).collect(),
+        }
+    }
+}
+
+impl DoorSensor {
+    pub fn topic(&self) -> &str {
+        match self {
+            DoorSensor::Reminder { topic, .. } => topic,
+            DoorSensor::Bathroom { topic, .. } => topic,
+        }
+    }
+
+    /// The name to use in announcements.
+    pub fn name(&self) -> &str {
+        match self {
+            DoorSensor::Reminder { name, .. } => name,
+            DoorSensor::Bathroom { .. } => "bathroom door",
+        }
+    }
+
+    pub fn quiet_after(&self) -> Option<u64> {
+        match self {
+            DoorSensor::Reminder { quiet_after, .. } => *quiet_after,
+            DoorSensor::Bathroom { quiet_after, .. } => *quiet_after,
+        }
+    }
+}
+
+/// Describe where in the file `span` starts.
+fn location(path: &str, text: &str, span: std::ops::Range<usize>) -> String {
+    let line = text[..span.start].matches('\n').count() + 1;
+    format!("{path}:{line}")
+}
+
+fn validate(config: &Config, path: &str, text: &str) -> Result<()> {
+    let error = |span, msg: String| anyhow!("{}: {msg}", location(path, text, span));
+
+    let mut message_locations = HashSet::new();
+    for location in &config.messages.locations {
+        if !message_locations.insert(location.get_ref()) {
+            let msg = format!("duplicate message location {}", location.get_ref());
+            return Err(error(location.span(), msg));
+        }
+    }
+
+    let mut ids = HashSet::new();
+    let lights = config.lights.iter().map(|l| (l.get_ref().id(), l.span()));
+    let devices = config.devices.iter().map(|d| (d.get_ref().id(), d.span()));
+    for (id, span) in lights.chain(devices) {
+        let name = format!("{}/{}", id.location, id.device);
+        if !ids.insert(name.clone()) {
+            return Err(error(span, format!("duplicate device {name}")));
+        }
+    }
+
+    for light in &config.lights {
+        if let Some(presence) = &light.get_ref().presence {
+            if presence.get_ref().threshold <= 0.0 {
+                let msg = "presence threshold must be greater then 0".to_string();
+                return Err(error(presence.span(), msg));
+            }
+            if presence.get_ref().off_threshold() < presence.get_ref().threshold {
+                let msg = "presence off_threshold must not be less then threshold".to_string();
+                return Err(error(presence.span(), msg));
+            }
+        }
+    }
+
+    for sensor in &config.door_sensors {
+        if sensor.get_ref().quiet_after() == Some(0) {
+            let msg = "door sensor quiet_after must be greater then 0".to_string();
+            return Err(error(sensor.span(), msg));
+        }
+        match sensor.get_ref() {
+            DoorSensor::Reminder { delay, repeat, .. }
+                if *delay == 0
+                    || repeat.intervals().is_empty()
+                    || repeat.intervals().iter().any(Duration::is_zero) =>
+            {
+                let msg = "door sensor delay and repeat must be greater then 0".to_string();
+                return Err(error(sensor.span(), msg));
+            }
+            DoorSensor::Bathroom { locations, .. } if locations.is_empty() => {
+                let msg = "bathroom door sensor needs at least one location".to_string();
+                return Err(error(sensor.span(), msg));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(quiet_hours) = &config.reminders.quiet_hours {
+        if let Err(err) = quiet_hours.get_ref().parse() {
+            return Err(error(
+                quiet_hours.span(),
+                format!("quiet hours have an {err}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse and validate the configuration.
+///
+/// Errors include `path` and the line number of the problem.
+pub fn parse(path: &str, text: &str) -> Result<Config> {
+    let config: Config = toml::from_str(text).map_err(|err| match err.span() {
+        Some(span) => anyhow!("{}: {}", location(path, text, span), err.message()),
+        None => anyhow!("{path}: {}", err.message()),
+    })?;
+    validate(&config, path, text)?;
+    Ok(config)
+}
+
+/// Load the configuration from a file.
+pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read config from {}", path.display()))?;
+    parse(&path.display().to_string(), &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_config() {
+        let text = include_str!("../config.toml");
+        let config = parse("config.toml", text).unwrap();
+        assert_eq!(config.lights.len(), 5);
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.door_sensors.len(), 2);
+    }
+
+    #[test]
+    fn test_errors_have_line_numbers() {
+        let text = "[[lights]]\nlocation = \"Brian\"\n\n[[lights]]\nlocation = \"Brian\"\n";
+        let err = parse("test.toml", text).unwrap_err();
+        assert_eq!(err.to_string(), "test.toml:4: duplicate device Brian/Light");
+
+        let text = "[[lights]]\nlocation = \"Brian\"\ncolour = \"red\"\n";
+        let err = parse("test.toml", text).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("test.toml:3: unknown field `colour`"));
+
+        let text = "[reminders]\nquiet_hours = { start = \"22:00\", end = \"7am\" }\n";
+        let err = parse("test.toml", text).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("test.toml:2: quiet hours have an invalid time 7am"));
+
+        let text = "[[door_sensors]]\ntype = \"window\"\ntopic = \"x\"\n";
+        let err = parse("test.toml", text).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("test.toml:2: unknown variant `window`"));
+    }
+}
@@ -1,10 +1,14 @@
+use std::time::Duration;
+
 use log::*;
 use robotica_node_rust::{
+    reload::Flows,
     sources::mqtt::{MqttOut, Subscriptions},
-    Pipe, RxPipe, TxPipe,
+    OverflowPolicy, Pipe, RxPipe, TxPipe,
 };
 
 use super::robotica::{string_to_message, Id};
+use crate::config::Messages;
 
 pub fn power_to_bool(value: String) -> bool {
     value == "ON"
@@ -46,17 +50,46 @@ pub fn message_location(
         .subscribe_to_string(&gate_topic)
         .map(power_to_bool);
 
-    rx.gate(do_gate)
+    rx.with_policy(OverflowPolicy::Backpressure)
+        .gate(do_gate)
         .map(move |v| string_to_message(v, &command_topic))
         .publish(mqtt);
 }
 
-pub fn message_sink(subscriptions: &mut Subscriptions, mqtt: &MqttOut) -> TxPipe<String> {
-    let pipe_start = Pipe::new();
+/// Join messages into one sentence, without doubling up on their trailing punctuation.
+pub fn join_messages(messages: Vec<String>) -> String {
+    messages
+        .iter()
+        .map(|m| m.trim_end_matches(['.', '!', '?']))
+        .collect::<Vec<_>>()
+        .join(". ")
+}
 
-    let pipe = pipe_start.to_rx_pipe().debug("outgoing message");
-    message_location(pipe.clone(), subscriptions, mqtt, "Brian");
-    message_location(pipe, subscriptions, mqtt, "Dining");
+/// Create the pipe for messages to announce, returns the sending and receiving ends.
+///
+/// Messages sent within a second of each other are announced together.
+pub fn message_sink() -> (TxPipe<String>, RxPipe<String>) {
+    let pipe_start = Pipe::new();
+    let pipe = pipe_start
+        .to_rx_pipe()
+        .buffer_time(Duration::from_secs(1))
+        .map(join_messages)
+        .debug("outgoing message");
+    (pipe_start.to_tx_pipe(), pipe)
+}
 
-    pipe_start.to_tx_pipe()
+pub fn message_locations(
+    flows: &mut Flows,
+    config: &Messages,
+    messages: &RxPipe<String>,
+    subscriptions: &mut Subscriptions,
+    mqtt: &MqttOut,
+) {
+    for location in &config.locations {
+        let location = location.get_ref();
+        let name = format!("messages/{location}");
+        flows.group(&name, location.clone(), |location| {
+            message_location(messages.clone(), subscriptions, mqtt, location)
+        });
+    }
 }
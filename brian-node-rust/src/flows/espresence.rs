@@ -23,6 +23,7 @@ pub fn brian_in_room(
     room: &str,
     subscriptions: &mut Subscriptions,
     threshold: f32,
+    off_threshold: f32,
 ) -> robotica_node_rust::RxPipe<bool> {
     let topic =
         &format!("espresense/devices/iBeacon:63a1368d-552b-4ea3-aed5-b5fefb2adf09-99-86/{room}");
@@ -37,9 +38,11 @@ pub fn brian_in_room(
                 .ok();
             b
         })
-        .map(move |b| b.distance <= threshold)
+        .map(|b| b.distance)
+        .hysteresis(threshold, off_threshold)
         .debug(&format!("Brian is in {room}"))
         .startup_delay(Duration::from_secs(10), false)
+        // Nothing is received once the beacon is out of range.
         .delay_cancel(Duration::from_secs(15))
         .debug(&format!("Brian is in {room} (delayed)"))
 }
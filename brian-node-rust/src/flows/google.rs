@@ -6,18 +6,16 @@ use chrono_tz::Tz;
 
 use log::*;
 use paho_mqtt::Message;
+use robotica_node_rust::filters::combine::combine_latest_partial2;
 use robotica_node_rust::filters::generic::if_else;
-use robotica_node_rust::recv;
-use robotica_node_rust::send_or_log;
+use robotica_node_rust::reload::Flows;
 use robotica_node_rust::sources::mqtt::MqttOut;
 use robotica_node_rust::sources::mqtt::Subscriptions;
 use robotica_node_rust::sources::timer;
-use robotica_node_rust::spawn;
-use robotica_node_rust::Pipe;
+use robotica_node_rust::OverflowPolicy;
 use robotica_node_rust::RxPipe;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use tokio::select;
 
 use super::espresence;
 use super::robotica::string_to_power;
@@ -29,6 +27,7 @@ use super::robotica::RoboticaAutoColorOut;
 use super::robotica::RoboticaColorOut;
 use super::robotica::RoboticaDeviceCommand;
 use super::robotica::RoboticaLightCommand;
+use crate::config::{Config, Light};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -47,18 +46,30 @@ struct GoogleCommand {
     online: bool,
 }
 
-pub fn start(subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
-    light(&Id::new("Brian", "Light"), subscriptions, mqtt_out);
-    light(&Id::new("Dining", "Light"), subscriptions, mqtt_out);
-    light(&Id::new("Passage", "Light"), subscriptions, mqtt_out);
-    light(&Id::new("Twins", "Light"), subscriptions, mqtt_out);
-    light(&Id::new("Akira", "Light"), subscriptions, mqtt_out);
+pub fn start(
+    flows: &mut Flows,
+    config: &Config,
+    subscriptions: &mut Subscriptions,
+    mqtt_out: &MqttOut,
+) {
+    for config in &config.lights {
+        let config = config.get_ref();
+        let name = format!("light/{}", config.location);
+        flows.group(&name, config.clone(), |config| {
+            light(config, subscriptions, mqtt_out)
+        });
+    }
 
-    device(&Id::new("Brian", "Fan"), subscriptions, mqtt_out);
-    device(&Id::new("Dining", "TvSwitch"), subscriptions, mqtt_out);
+    for config in &config.devices {
+        let config = config.get_ref();
+        let name = format!("device/{}/{}", config.location, config.device);
+        flows.group(&name, config.clone(), |config| {
+            device(&config.id(), subscriptions, mqtt_out)
+        });
+    }
 }
 
-fn light_google_to_robotica(payload: String, id: &Id) -> Option<Message> {
+fn light_google_to_robotica(payload: String, id: &Id, scene: &str) -> Option<Message> {
     let mut color = RoboticaColorOut {
         hue: 0,
         saturation: 0,
@@ -94,15 +105,10 @@ fn light_google_to_robotica(payload: String, id: &Id) -> Option<Message> {
 
     let action = if gc.on { None } else { Some(Action::TurnOff) };
 
-    let scene = match (id.location.as_str(), id.device.as_str()) {
-        ("Brian", "Light") => "auto".to_string(),
-        (_, _) => "default".to_string(),
-    };
-
     let command = RoboticaLightCommand {
         action,
         color: Some(color),
-        scene: Some(scene),
+        scene: Some(scene.to_string()),
     };
 
     let topic = id.get_command_topic(&[]);
@@ -157,13 +163,13 @@ fn robotica_to_google(power: Power, id: &Id) -> Message {
     Message::new(topic, payload, 0)
 }
 
-fn timer_to_color(id: &Id) -> RoboticaAutoColor {
+fn timer_to_color(dim_at_night: bool) -> RoboticaAutoColor {
     let now: DateTime<Utc> = Utc::now();
     let tz: Tz = "Australia/Melbourne".parse().unwrap();
     let local_now = now.with_timezone(&tz);
     let hour = local_now.hour();
 
-    let brightness = if id.location == "Brian" {
+    let brightness = if dim_at_night {
         match hour {
             h if !(5..22).contains(&h) => 5,
             h if !(6..21).contains(&h) => 15,
@@ -203,13 +209,17 @@ fn color_to_message(color: RoboticaAutoColor, id: &Id) -> Message {
     Message::new_retained(topic, payload, 0)
 }
 
-fn light(id: &Id, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
+fn light(config: &Light, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
+    let id = &config.id();
+
     {
         let id = (*id).clone();
+        let scene = config.scene.clone();
         let topic = id.get_google_out_topic();
         subscriptions
             .subscribe_to_string(&topic)
-            .filter_map(move |payload| light_google_to_robotica(payload, &id))
+            .with_policy(OverflowPolicy::Backpressure)
+            .filter_map(move |payload| light_google_to_robotica(payload, &id, &scene))
             .publish(mqtt_out);
     }
 
@@ -229,10 +239,17 @@ fn light(id: &Id, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
             .publish(mqtt_out);
     }
 
-    let gate = match id.location.as_str() {
-        "Brian" => espresence::brian_in_room("brian", subscriptions, 20.0),
-        "Passage" => espresence::brian_in_room("passage", subscriptions, 1.5),
-        _ => timer::timer(Duration::from_secs(60), true),
+    let gate = match &config.presence {
+        Some(presence) => {
+            let presence = presence.get_ref();
+            espresence::brian_in_room(
+                &presence.room,
+                subscriptions,
+                presence.threshold,
+                presence.off_threshold(),
+            )
+        }
+        None => timer::timer(Duration::from_secs(60), true),
     };
 
     {
@@ -250,9 +267,9 @@ fn light(id: &Id, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
             },
         );
 
-        let id1 = (*id).clone();
+        let dim_at_night = config.dim_at_night;
         let on_color =
-            timer::timer(Duration::from_secs(60), true).map(move |_| timer_to_color(&id1));
+            timer::timer(Duration::from_secs(60), true).map(move |_| timer_to_color(dim_at_night));
 
         let id2 = (*id).clone();
         if_else(gate, on_color, off_color)
@@ -270,6 +287,7 @@ fn device(id: &Id, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
 
         subscriptions
             .subscribe_to_string(&topic)
+            .with_policy(OverflowPolicy::Backpressure)
             .filter_map(move |payload| device_google_to_robotica(payload, &id))
             .publish(mqtt_out);
     }
@@ -286,44 +304,26 @@ fn device(id: &Id, subscriptions: &mut Subscriptions, mqtt_out: &MqttOut) {
     }
 }
 
-fn light_power(priorities: RxPipe<Vec<u16>>, power: RxPipe<String>) -> RxPipe<Power> {
-    let output = Pipe::new();
-    let tx = output.get_tx();
-    let mut priorities = priorities.subscribe();
-    let mut power = power.subscribe();
-
-    spawn(async move {
-        let mut the_priorities: Option<Vec<u16>> = None;
-        let mut the_power: Option<String> = None;
-
-        loop {
-            select! {
-                Ok(priorities) = recv(&mut priorities) => { the_priorities = Some(priorities)},
-                Ok(power) = recv(&mut power) => { the_power = Some(power)},
-                else => { break; }
-            }
-
-            let value = match (&the_priorities, the_power.as_deref()) {
-                (_, None) => None,
-                (_, Some("HARD_OFF")) => Some(Power::HardOff),
-                (_, Some("ERROR")) => Some(Power::Error),
-                (None, _) => None,
-                (Some(priorities), Some("ON")) if priorities.is_empty() => Some(Power::On),
-                (Some(priorities), Some("OFF")) if priorities.is_empty() => Some(Power::Off),
-                (Some(priorities), _) => {
-                    if priorities.contains(&100) {
-                        Some(Power::On)
-                    } else {
-                        Some(Power::Off)
-                    }
-                }
-            };
-
-            if let Some(value) = value {
-                send_or_log(&tx, value);
+fn light_power_value((priorities, power): (Option<Vec<u16>>, Option<String>)) -> Option<Power> {
+    match (&priorities, power.as_deref()) {
+        (_, None) => None,
+        (_, Some("HARD_OFF")) => Some(Power::HardOff),
+        (_, Some("ERROR")) => Some(Power::Error),
+        (None, _) => None,
+        (Some(priorities), Some("ON")) if priorities.is_empty() => Some(Power::On),
+        (Some(priorities), Some("OFF")) if priorities.is_empty() => Some(Power::Off),
+        (Some(priorities), _) => {
+            if priorities.contains(&100) {
+                Some(Power::On)
+            } else {
+                Some(Power::Off)
             }
         }
-    });
+    }
+}
 
-    output.to_rx_pipe()
+fn light_power(priorities: RxPipe<Vec<u16>>, power: RxPipe<String>) -> RxPipe<Power> {
+    combine_latest_partial2(priorities, power)
+        .filter_map(light_power_value)
+        .named("light_power")
 }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use paho_mqtt::Message;
 use robotica_node_rust::{
@@ -6,9 +7,11 @@ use robotica_node_rust::{
         life360::{self, Member},
         mqtt::MqttOut,
     },
-    TxPipe,
+    RxPipe, TxPipe,
 };
 
+use super::common::join_messages;
+
 type MemberIndex = HashMap<String, Member>;
 
 #[derive(Clone)]
@@ -91,8 +94,21 @@ fn changed_to_message(changed: Changed) -> Option<String> {
     }
 }
 
+/// Announce location changes, one message for all the members changed by the same poll.
+fn announcements(
+    circles: &RxPipe<(Option<Member>, Member)>,
+    polled: &RxPipe<()>,
+) -> RxPipe<String> {
+    circles
+        .map(member_location_changed)
+        .filter_map(changed_to_message)
+        .buffer_until(polled)
+        .map(join_messages)
+}
+
 pub fn start(mqtt_out: &MqttOut, message_sink: &TxPipe<String>) {
-    let circles = life360::circles().map_with_state(HashMap::new(), member_diff);
+    let (circles, polled) = life360::circles_with_polls();
+    let circles = circles.map_with_state_persisted("life360/members", HashMap::new(), member_diff);
 
     circles
         .filter_map(member_changed)
@@ -103,8 +119,70 @@ pub fn start(mqtt_out: &MqttOut, message_sink: &TxPipe<String>) {
         })
         .publish(mqtt_out);
 
-    circles
-        .map(member_location_changed)
-        .filter_map(changed_to_message)
-        .copy_to(message_sink);
+    // Give the members of the poll time to get through the flow before the batch is sent.
+    let polled = polled.delay(Duration::from_secs(1));
+    announcements(&circles, &polled).copy_to(message_sink);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use robotica_node_rust::Pipe;
+    use serde_json::json;
+    use tokio::time::sleep;
+
+    fn member(first_name: &str, location: Option<&str>) -> Member {
+        serde_json::from_value(json!({
+            "location": {
+                "latitude": "0", "longitude": "0", "accuracy": "10", "endTimestamp": "0",
+                "timestamp": "0", "name": location, "shortAddress": "", "inTransit": "0",
+                "battery": "50", "charge": "0", "wifiState": "1", "speed": 0.0,
+                "isDriving": "0",
+            },
+            "communications": [], "createdAt": "0", "id": first_name,
+            "firstName": first_name, "lastName": "Smith", "isAdmin": "0", "avatar": "",
+            "loginEmail": "", "loginPhone": "",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_announcement_per_poll() {
+        let circles = Pipe::new();
+        let polled = Pipe::new();
+        let mut messages = announcements(&circles.to_rx_pipe(), &polled.to_rx_pipe()).subscribe();
+
+        let tx = circles.get_tx();
+        let tx_polled = polled.get_tx();
+        let moved = |name, old, new| (Some(member(name, old)), member(name, new));
+
+        tx.send(moved("Brian", Some("Home"), Some("Work")))
+            .await
+            .unwrap();
+        tx.send(moved("Jane", Some("Work"), Some("Home.")))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        tx_polled.send(()).await.unwrap();
+
+        // A quiet poll does not announce anything.
+        sleep(Duration::from_millis(10)).await;
+        tx_polled.send(()).await.unwrap();
+
+        tx.send(moved("Sam", None, Some("School"))).await.unwrap();
+        sleep(Duration::from_millis(10)).await;
+        tx_polled.send(()).await.unwrap();
+
+        assert_eq!(
+            messages.recv().await.unwrap(),
+            "Brian Smith has left Home and arrived at Work. \
+             Jane Smith has left Work and arrived at Home"
+        );
+        assert_eq!(
+            messages.recv().await.unwrap(),
+            "Sam Smith has arrived at School"
+        );
+        sleep(Duration::from_millis(10)).await;
+        assert!(messages.try_recv().is_err());
+    }
 }
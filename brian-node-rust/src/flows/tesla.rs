@@ -2,11 +2,13 @@ use std::time::Duration;
 
 use robotica_node_rust::{
     filters::teslamate::{is_insecure, requires_plugin},
+    reload::Flows,
     sources::mqtt::Subscriptions,
     TxPipe,
 };
 
 use super::common::{power_to_bool, string_to_bool, string_to_integer};
+use crate::config::{Config, Reminders};
 
 fn geofence_to_message((old, new): (Option<String>, String)) -> Option<String> {
     match (old.as_deref(), new.as_str()) {
@@ -28,20 +30,38 @@ fn plugged_in_to_message((old, new): (Option<bool>, bool)) -> Option<String> {
     }
 }
 
-pub fn start(subscriptions: &mut Subscriptions, message_sink: &TxPipe<String>) {
-    car(1, subscriptions, message_sink);
+pub fn start(
+    flows: &mut Flows,
+    config: &Config,
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+) {
+    let car_id = 1;
+    let name = format!("tesla/{car_id}");
+    flows.group(&name, config.reminders.clone(), |reminders| {
+        car(car_id, reminders, subscriptions, message_sink)
+    });
 }
 
-fn car(car_id: usize, subscriptions: &mut Subscriptions, message_sink: &TxPipe<String>) {
+fn car(
+    car_id: usize,
+    reminders: &Reminders,
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+) {
+    let policy = reminders.policy(vec![Duration::from_secs(60 * 10)]);
+
     let topic = format!("teslamate/cars/{car_id}/battery_level");
     let battery_level = subscriptions
         .subscribe_to_string(&topic)
-        .filter_map(string_to_integer);
+        .filter_map(string_to_integer)
+        .named("battery_level");
 
     let topic = format!("teslamate/cars/{car_id}/plugged_in");
     let plugged_in = subscriptions
         .subscribe_to_string(&topic)
-        .filter_map(string_to_bool);
+        .filter_map(string_to_bool)
+        .named("plugged_in");
 
     let topic = format!("teslamate/cars/{car_id}/geofence");
     let geofence = subscriptions.subscribe_to_string(&topic);
@@ -49,36 +69,44 @@ fn car(car_id: usize, subscriptions: &mut Subscriptions, message_sink: &TxPipe<S
     let topic = format!("teslamate/cars/{car_id}/is_user_present");
     let is_user_present = subscriptions
         .subscribe_to_string(&topic)
-        .filter_map(string_to_bool);
+        .filter_map(string_to_bool)
+        .named("is_user_present");
 
     let topic = format!("teslamate/cars/{car_id}/locked");
     let locked = subscriptions
         .subscribe_to_string(&topic)
-        .filter_map(string_to_bool);
+        .filter_map(string_to_bool)
+        .named("locked");
 
     let topic = String::from("state/Brian/TeslaReminder/power");
-    let reminder = subscriptions.subscribe_to_string(&topic).map(power_to_bool);
+    let reminder = subscriptions
+        .subscribe_to_string(&topic)
+        .map(power_to_bool)
+        .named("reminder");
 
     geofence
         .debug("geofence")
-        .diff()
+        .diff_persisted(&format!("tesla/{car_id}/geofence"), None)
         .filter_map(geofence_to_message)
         .copy_to(message_sink);
 
     plugged_in
         .debug("plugged_in")
-        .diff()
+        .diff_persisted(&format!("tesla/{car_id}/plugged_in"), None)
         .filter_map(plugged_in_to_message)
         .copy_to(message_sink);
 
     is_insecure(is_user_present, locked)
         .debug("is_insecure")
-        .delay_true(Duration::from_secs(60 * 2))
+        .delay_true_persisted(
+            &format!("tesla/{car_id}/is_insecure/delay"),
+            Duration::from_secs(60 * 2),
+        )
         .debug("is_insecure delayed")
-        .diff_with_initial_value(Some(false))
+        .diff_persisted(&format!("tesla/{car_id}/is_insecure"), Some(false))
         .changed()
         .debug("is_insecure changed")
-        .timer_true(Duration::from_secs(60 * 10))
+        .reminder(policy.clone())
         .map(|v| {
             if v {
                 "The tesla is lonely and insecure".to_string()
@@ -90,9 +118,9 @@ fn car(car_id: usize, subscriptions: &mut Subscriptions, message_sink: &TxPipe<S
 
     requires_plugin(battery_level, plugged_in, geofence, reminder)
         .debug("requires_plugin")
-        .diff_with_initial_value(Some(false))
+        .diff_persisted(&format!("tesla/{car_id}/requires_plugin"), Some(false))
         .changed()
-        .timer_true(Duration::from_secs(60 * 10))
+        .reminder(policy)
         .map(|v| {
             if v {
                 "The tesla requires leashing".to_string()
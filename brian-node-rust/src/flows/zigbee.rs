@@ -2,11 +2,15 @@ use std::time::Duration;
 
 use paho_mqtt::Message;
 use robotica_node_rust::{
+    filters::reminder::ReminderPolicy,
+    reload::Flows,
     sources::mqtt::{MqttOut, Subscriptions},
-    RxPipe, TxPipe,
+    OverflowPolicy, RxPipe, TxPipe,
 };
 use serde::Deserialize;
 
+use crate::config::{Config, DoorSensor, Reminders};
+
 use super::{
     common::power_to_bool,
     robotica::{string_to_message, Action, Id, RoboticaDeviceCommand, RoboticaLightCommand},
@@ -51,7 +55,7 @@ pub fn bathroom_location(
         .subscribe_to_string(&gate_topic)
         .map(power_to_bool);
 
-    let gate_result = rx.gate(do_gate);
+    let gate_result = rx.with_policy(OverflowPolicy::Backpressure).gate(do_gate);
 
     gate_result
         .filter(move |msg| *msg)
@@ -75,29 +79,122 @@ pub fn bathroom_location(
         .publish(mqtt);
 }
 
-pub fn start(subscriptions: &mut Subscriptions, message_sink: &TxPipe<String>, mqtt_out: &MqttOut) {
+pub fn start(
+    flows: &mut Flows,
+    config: &Config,
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+    mqtt_out: &MqttOut,
+) {
+    for sensor in &config.door_sensors {
+        let sensor = sensor.get_ref();
+        let name = format!("door_sensor/{}", sensor.topic());
+        let params = (sensor.clone(), config.reminders.clone());
+        flows.group(&name, params, |(sensor, reminders)| {
+            door_sensor(sensor, reminders, subscriptions, message_sink, mqtt_out)
+        });
+    }
+}
+
+fn door_sensor(
+    sensor: &DoorSensor,
+    reminders: &Reminders,
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+    mqtt_out: &MqttOut,
+) {
+    if let Some(quiet_after) = sensor.quiet_after() {
+        door_sensor_quiet(
+            subscriptions,
+            message_sink,
+            sensor.topic(),
+            sensor.name(),
+            Duration::from_secs(quiet_after),
+        );
+    }
+
+    match sensor {
+        DoorSensor::Reminder {
+            topic,
+            name,
+            delay,
+            repeat,
+            ..
+        } => door_reminder(
+            subscriptions,
+            message_sink,
+            topic,
+            name,
+            Duration::from_secs(*delay),
+            reminders.policy(repeat.intervals()),
+        ),
+        DoorSensor::Bathroom {
+            topic,
+            alert_light,
+            locations,
+            ..
+        } => bathroom_door(subscriptions, mqtt_out, topic, alert_light, locations),
+    }
+}
+
+fn door_sensor_quiet(
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+    topic: &str,
+    name: &str,
+    quiet_after: Duration,
+) {
+    let name = name.to_string();
     subscriptions
-        .subscribe_to_string("zigbee2mqtt/Dining/door")
+        .subscribe_to_string(topic)
+        .stale(quiet_after)
+        .map(move |stale| match stale {
+            true => format!("The {name} sensor has gone quiet"),
+            false => format!("The {name} sensor is working again"),
+        })
+        .copy_to(message_sink);
+}
+
+fn door_reminder(
+    subscriptions: &mut Subscriptions,
+    message_sink: &TxPipe<String>,
+    topic: &str,
+    name: &str,
+    delay: Duration,
+    policy: ReminderPolicy,
+) {
+    let name = name.to_string();
+    subscriptions
+        .subscribe_to_string(topic)
         .filter_map(third_reality_door_sensor)
         .map(|door_sensor| !door_sensor.contact)
-        .delay_true(Duration::from_secs(30))
+        .delay_true(delay)
         .diff_with_initial_value(Some(false))
         .changed()
-        .timer_true(Duration::from_secs(60))
-        .map(|state| match state {
-            true => "Please close the front door".to_string(),
-            false => "Thank-you for closing the front door".to_string(),
+        .reminder(policy)
+        .map(move |state| match state {
+            true => format!("Please close the {name}"),
+            false => format!("Thank-you for closing the {name}"),
         })
         .copy_to(message_sink);
+}
 
+fn bathroom_door(
+    subscriptions: &mut Subscriptions,
+    mqtt_out: &MqttOut,
+    topic: &str,
+    alert_light: &str,
+    locations: &[String],
+) {
     let bathroom = subscriptions
-        .subscribe_to_string("zigbee2mqtt/Bathroom/door")
+        .subscribe_to_string(topic)
         .filter_map(third_reality_door_sensor)
         .map(|door_sensor| !door_sensor.contact)
         .diff()
         .changed()
         .debug("bathroom door open");
 
+    let alert_topic = Id::new(alert_light, "Light").get_command_topic(&[]);
     bathroom
         .map(|contact| match contact {
             // Door is open
@@ -113,14 +210,13 @@ pub fn start(subscriptions: &mut Subscriptions, message_sink: &TxPipe<String>, m
                 scene: Some("alert_bathroom".to_string()),
             },
         })
-        .map(|command| {
-            let id = Id::new("Passage", "Light");
-            let topic = id.get_command_topic(&[]);
+        .map(move |command| {
             let payload = serde_json::to_string(&command).unwrap();
-            Message::new(topic, payload, 0)
+            Message::new(alert_topic.clone(), payload, 0)
         })
         .publish(mqtt_out);
 
-    bathroom_location(bathroom.clone(), subscriptions, mqtt_out, "Brian");
-    bathroom_location(bathroom, subscriptions, mqtt_out, "Dining");
+    for location in locations {
+        bathroom_location(bathroom.clone(), subscriptions, mqtt_out, location);
+    }
 }
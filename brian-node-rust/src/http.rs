@@ -1,13 +1,34 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
 
-use robotica_node_rust::spawn;
+use robotica_node_rust::{metrics, registry, reload, runtime, spawn, trace};
 use warp::Filter;
 
 pub async fn start() {
     spawn(async {
         let hello = warp::path::end().map(|| "Hello! You were not invited. Go away.");
+        let dot = warp::path!("flows" / "dot").map(registry::to_dot);
+        let mermaid = warp::path!("flows" / "mermaid").map(registry::to_mermaid);
+        let metrics = warp::path!("metrics").map(|| {
+            warp::reply::with_header(
+                metrics::to_prometheus(),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        });
+        let why = warp::path!("trace" / "why")
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|query: HashMap<String, String>| {
+                let topic = query.get("topic").map(String::as_str).unwrap_or_default();
+                trace::why(topic).unwrap_or_else(|| format!("Nothing published to {topic}\n"))
+            });
+        let reload = warp::post().and(warp::path!("reload")).map(|| {
+            reload::request_reload();
+            "Reloading the config\n"
+        });
 
         let addr = IpAddr::from_str("::0").unwrap();
-        warp::serve(hello).run((addr, 4000)).await;
+        let (_, server) = warp::serve(hello.or(dot).or(mermaid).or(metrics).or(why).or(reload))
+            .bind_with_graceful_shutdown((addr, 4000), runtime::wait_for_shutdown());
+        server.await;
     });
 }
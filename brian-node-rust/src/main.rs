@@ -1,39 +1,107 @@
+mod config;
 mod flows;
 mod http;
 
 use anyhow::Result;
-use flows::common::message_sink;
+use config::Config;
+use flows::common::{message_locations, message_sink};
 use flows::google;
 use flows::life360;
 use flows::tesla;
 use flows::zigbee;
+use log::*;
+use robotica_node_rust::persist;
+use robotica_node_rust::reload::{self, Flows};
+use robotica_node_rust::runtime;
 use robotica_node_rust::sources::mqtt::MqttOut;
+use robotica_node_rust::spawn;
+use robotica_node_rust::trace;
+use robotica_node_rust::{RxPipe, TxPipe};
+use std::env;
+use std::time::Duration;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
 
 use robotica_node_rust::sources::mqtt::{MqttClient, Subscriptions};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+
+    let config_file = env::var("CONFIG_FILE").unwrap_or_else(|_| "config.toml".to_string());
+    let config = config::load(&config_file)?;
+
     http::start().await;
 
+    let state_file = env::var("STATE_FILE").unwrap_or_else(|_| "state.json".to_string());
+    persist::start(state_file, Duration::from_secs(60))?;
+    trace::enable();
+
     let mut mqtt = MqttClient::new().await;
-    let tx = mqtt.get_mqtt_out();
+    if let Ok(topic) = env::var("MQTT_LEADER_TOPIC") {
+        mqtt.enable_leader_election(&topic, Duration::from_secs(10));
+    }
 
-    let subscriptions: Subscriptions = setup_pipes(&tx);
-    mqtt.connect(subscriptions);
-    mqtt.wait().await;
+    let mut pipes = Pipes {
+        flows: Flows::new(),
+        subscriptions: Subscriptions::new(),
+        mqtt: mqtt.get_mqtt_out(),
+        message_sink: message_sink(),
+    };
+    pipes.setup(&config);
+    mqtt.connect(pipes.subscriptions.clone());
+
+    spawn(reload_on_request(config_file, pipes));
+
+    runtime::run_until_signal(Duration::from_secs(20)).await;
 
     Ok(())
 }
 
-fn setup_pipes(mqtt: &MqttOut) -> Subscriptions {
-    let mut subscriptions: Subscriptions = Subscriptions::new();
-    let message_sink = message_sink(&mut subscriptions, mqtt);
+/// Everything needed to build the flows again.
+struct Pipes {
+    flows: Flows,
+    subscriptions: Subscriptions,
+    mqtt: MqttOut,
+    message_sink: (TxPipe<String>, RxPipe<String>),
+}
+
+impl Pipes {
+    fn setup(&mut self, config: &Config) {
+        let (message_sink, _) = &self.message_sink;
+        life360::start(&self.mqtt, message_sink);
+        self.setup_reloadable(config);
+    }
+
+    /// Build the flows that come from the config, only rebuilding what changed.
+    fn setup_reloadable(&mut self, config: &Config) {
+        let flows = &mut self.flows;
+        let subscriptions = &mut self.subscriptions;
+        let mqtt = &self.mqtt;
+        let (message_sink, messages) = &self.message_sink;
+
+        message_locations(flows, &config.messages, messages, subscriptions, mqtt);
+        tesla::start(flows, config, subscriptions, message_sink);
+        zigbee::start(flows, config, subscriptions, message_sink, mqtt);
+        google::start(flows, config, subscriptions, mqtt);
+        flows.finish();
+    }
+}
+
+/// Reload the config on SIGHUP or when asked to over HTTP, until shutdown.
+async fn reload_on_request(config_file: String, mut pipes: Pipes) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Cannot install SIGHUP handler");
 
-    tesla::start(&mut subscriptions, &message_sink);
-    life360::start(mqtt, &message_sink);
-    zigbee::start(&mut subscriptions, &message_sink, mqtt);
-    google::start(&mut subscriptions, mqtt);
+    loop {
+        select! {
+            _ = sighup.recv() => info!("Received SIGHUP, reloading {config_file}"),
+            _ = reload::wait_for_reload() => info!("Reload requested, reloading {config_file}"),
+            _ = runtime::wait_for_shutdown() => { break; }
+        }
 
-    subscriptions
+        match config::load(&config_file) {
+            Ok(config) => pipes.setup_reloadable(&config),
+            Err(err) => error!("Keeping the old flows, cannot reload config: {err:#}"),
+        }
+    }
 }
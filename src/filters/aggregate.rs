@@ -0,0 +1,272 @@
+//! Statistics over a sliding window of recent values.
+//!
+//! A window holds either the last `n` values, or the values received in the last `duration`.
+//! Old values are only dropped when a new value arrives, so a time window never sends on its
+//! own.
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::registry::register_node;
+use crate::{recv, send_or_log, spawn, Receiver, RxPipe, Sender};
+
+/// A numeric value that can be aggregated.
+pub trait Number: Copy + PartialOrd + Debug + Send + Sync + 'static {
+    /// Convert to a float, for averages and rates.
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! number {
+    ($($T:ty),+) => {
+        $(impl Number for $T {
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        })+
+    };
+}
+
+number!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+/// Which values are aggregated.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Window {
+    /// The last `n` values, `n` must be greater then 0.
+    Count(usize),
+    /// The values received in the last `duration`, including the latest.
+    Time(Duration),
+}
+
+/// The values in a window, oldest first.
+struct Samples<T> {
+    window: Window,
+    values: VecDeque<(Instant, T)>,
+}
+
+impl<T: Number> Samples<T> {
+    fn new(window: Window) -> Self {
+        Samples {
+            window,
+            values: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, now: Instant, value: T) {
+        self.values.push_back((now, value));
+        match self.window {
+            Window::Count(n) => {
+                while self.values.len() > n {
+                    self.values.pop_front();
+                }
+            }
+            Window::Time(duration) => {
+                while let Some((t, _)) = self.values.front() {
+                    if now.duration_since(*t) <= duration {
+                        break;
+                    }
+                    self.values.pop_front();
+                }
+            }
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = T> + '_ {
+        self.values.iter().map(|(_, v)| *v)
+    }
+
+    fn average(&self) -> Option<f64> {
+        let sum: f64 = self.values().map(Number::to_f64).sum();
+        (!self.values.is_empty()).then(|| sum / self.values.len() as f64)
+    }
+
+    fn min(&self) -> Option<T> {
+        self.values().reduce(|a, b| {
+            if b.partial_cmp(&a) == Some(Ordering::Less) {
+                b
+            } else {
+                a
+            }
+        })
+    }
+
+    fn max(&self) -> Option<T> {
+        self.values().reduce(|a, b| {
+            if b.partial_cmp(&a) == Some(Ordering::Greater) {
+                b
+            } else {
+                a
+            }
+        })
+    }
+
+    /// Change per second between the oldest and the latest value.
+    fn rate_of_change(&self) -> Option<f64> {
+        let (first_t, first) = self.values.front()?;
+        let (last_t, last) = self.values.back()?;
+        let elapsed = last_t.duration_since(*first_t).as_secs_f64();
+        (elapsed > 0.0).then(|| (last.to_f64() - first.to_f64()) / elapsed)
+    }
+
+    /// The nearest rank percentile.
+    fn percentile(&self, percentile: f64) -> Option<T> {
+        let mut sorted: Vec<T> = self.values().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        let index = rank.clamp(1, sorted.len().max(1)) - 1;
+        sorted.get(index).copied()
+    }
+}
+
+fn aggregate<T: Number, U: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<U>,
+    window: Window,
+    statistic: impl Fn(&Samples<T>) -> Option<U> + Send + 'static,
+) {
+    spawn(async move {
+        let mut samples = Samples::new(window);
+
+        while let Ok(v) = recv(&mut input).await {
+            samples.push(Instant::now(), v);
+            if let Some(v) = statistic(&samples) {
+                send_or_log(&output, v).await;
+            }
+        }
+    });
+}
+
+impl<T: Number> RxPipe<T> {
+    fn aggregate<U: Send + Clone + 'static>(
+        &self,
+        name: &str,
+        window: Window,
+        statistic: impl Fn(&Samples<T>) -> Option<U> + Send + 'static,
+    ) -> RxPipe<U> {
+        assert!(
+            window != Window::Count(0),
+            "{name} needs a count greater then 0"
+        );
+        let output = self.new_output();
+        register_node(name, &[self.id()], &[output.id()]);
+        aggregate(self.subscribe(), output.get_tx(), window, statistic);
+        output.to_rx_pipe()
+    }
+
+    /// Send the average of the values in the window, after every value.
+    pub fn moving_average(&self, window: Window) -> RxPipe<f64> {
+        self.aggregate("moving_average", window, Samples::average)
+    }
+
+    /// Send the smallest value in the window, after every value.
+    pub fn window_min(&self, window: Window) -> RxPipe<T> {
+        self.aggregate("window_min", window, Samples::min)
+    }
+
+    /// Send the largest value in the window, after every value.
+    pub fn window_max(&self, window: Window) -> RxPipe<T> {
+        self.aggregate("window_max", window, Samples::max)
+    }
+
+    /// Send the change per second between the oldest and latest value in the window.
+    ///
+    /// Nothing is sent until the window spans some time.
+    pub fn rate_of_change(&self, window: Window) -> RxPipe<f64> {
+        self.aggregate("rate_of_change", window, Samples::rate_of_change)
+    }
+
+    /// Send the `percentile` of the values in the window, after every value.
+    ///
+    /// Uses the nearest rank method, so the result is always one of the values. `percentile`
+    /// is between 0 and 100.
+    pub fn percentile(&self, window: Window, percentile: f64) -> RxPipe<T> {
+        assert!(
+            (0.0..=100.0).contains(&percentile),
+            "percentile {percentile} must be between 0 and 100"
+        );
+        self.aggregate("percentile", window, move |samples| {
+            samples.percentile(percentile)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+    use crate::Pipe;
+
+    fn samples<T: Number>(window: Window, values: &[T]) -> Samples<T> {
+        let now = Instant::now();
+        let mut samples = Samples::new(window);
+        for v in values {
+            samples.push(now, *v);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_count_window() {
+        let s = samples(Window::Count(3), &[5, 1, 4, 2, 3]);
+        assert_eq!(s.values().collect::<Vec<_>>(), vec![4, 2, 3]);
+        assert_eq!(s.average(), Some(3.0));
+        assert_eq!(s.min(), Some(2));
+        assert_eq!(s.max(), Some(4));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let s = samples(Window::Count(10), &[15.0, 20.0, 35.0, 40.0, 50.0]);
+        assert_eq!(s.percentile(0.0), Some(15.0));
+        assert_eq!(s.percentile(30.0), Some(20.0));
+        assert_eq!(s.percentile(50.0), Some(35.0));
+        assert_eq!(s.percentile(100.0), Some(50.0));
+        assert_eq!(samples::<u8>(Window::Count(10), &[]).percentile(50.0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "moving_average needs a count greater then 0")]
+    fn test_empty_count_window() {
+        Pipe::<f64>::new()
+            .to_rx_pipe()
+            .moving_average(Window::Count(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_window() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        aggregate(in_rx, out_tx, Window::Time(ms(100)), Samples::max);
+
+        injector.script([(ms(0), 10u32), (ms(50), 2), (ms(120), 3), (ms(300), 1)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[(ms(0), 10), (ms(50), 10), (ms(120), 3), (ms(300), 1)],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_of_change() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        aggregate(in_rx, out_tx, Window::Count(2), Samples::rate_of_change);
+
+        injector.script([(ms(0), 80i32), (ms(500), 79), (ms(1500), 81)]);
+
+        recorder
+            .assert_recorded_by(ms(2000), &[(ms(500), -2.0), (ms(1500), 2.0)])
+            .await;
+    }
+}
@@ -0,0 +1,276 @@
+//! Map values with async callbacks, such as HTTP requests or file writes.
+//!
+//! Several calls can run at the same time. Calls that fail or time out are sent to a
+//! separate error pipe, so they can be logged or announced.
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::registry::register_node;
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::{recv, send_or_log, Pipe, Receiver, RxPipe, Sender};
+
+/// How the callbacks of [RxPipe::map_async] and friends are run.
+#[derive(Debug, Clone)]
+pub struct AsyncOptions {
+    /// Maximum number of calls running at the same time, must be greater then 0.
+    pub concurrency: usize,
+    /// Send results in the order the values were received, rather than as they finish.
+    pub ordered: bool,
+    /// Give up on calls that take longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AsyncOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            ordered: true,
+            timeout: None,
+        }
+    }
+}
+
+/// A call that did not produce a value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MapError<E = Infallible> {
+    /// The callback returned an error.
+    Failed(E),
+    /// The callback did not finish in time.
+    TimedOut(Duration),
+}
+
+impl<E: Display> Display for MapError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Failed(err) => write!(f, "{err}"),
+            MapError::TimedOut(timeout) => write!(f, "timed out after {timeout:?}"),
+        }
+    }
+}
+
+fn map_async<T, U, E, Fut>(
+    input: Receiver<T>,
+    output: Sender<U>,
+    errors: Sender<MapError<E>>,
+    options: AsyncOptions,
+    callback: impl Fn(T) -> Fut + Send + Sync + 'static,
+) where
+    T: Send + Clone + 'static,
+    U: Send + Clone + 'static,
+    E: Send + Clone + 'static,
+    Fut: Future<Output = Result<Option<U>, E>> + Send + 'static,
+{
+    let input = Arc::new(Mutex::new(input));
+    let callback = Arc::new(callback);
+    supervise("map_async", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let errors = errors.clone();
+        let callback = callback.clone();
+        let options = options.clone();
+        async move {
+            let mut input = input.lock().await;
+            let timeout = options.timeout;
+            let values = stream::unfold(&mut *input, |rx| async move {
+                let v = recv(rx).await.ok()?;
+                Some((v, rx))
+            });
+            let calls = values.map(move |v| {
+                let call = callback(v);
+                async move {
+                    let result = match timeout {
+                        Some(timeout) => time::timeout(timeout, call)
+                            .await
+                            .map_err(|_| MapError::TimedOut(timeout))?,
+                        None => call.await,
+                    };
+                    result.map_err(MapError::Failed)
+                }
+            });
+
+            let mut results: BoxStream<_> = if options.ordered {
+                calls.buffered(options.concurrency).boxed()
+            } else {
+                calls.buffer_unordered(options.concurrency).boxed()
+            };
+
+            while let Some(result) = results.next().await {
+                match result {
+                    Ok(Some(v)) => send_or_log(&output, v).await,
+                    Ok(None) => {}
+                    Err(err) => send_or_log(&errors, err).await,
+                }
+            }
+        }
+    });
+}
+
+impl<T: Send + Clone + 'static> RxPipe<T> {
+    fn map_async_node<U, E, Fut>(
+        &self,
+        kind: &str,
+        options: AsyncOptions,
+        callback: impl Fn(T) -> Fut + Send + Sync + 'static,
+    ) -> (RxPipe<U>, RxPipe<MapError<E>>)
+    where
+        U: Send + Clone + 'static,
+        E: Send + Clone + 'static,
+        Fut: Future<Output = Result<Option<U>, E>> + Send + 'static,
+    {
+        assert!(
+            options.concurrency > 0,
+            "{kind} needs a concurrency greater then 0"
+        );
+        let output = self.new_output();
+        let errors = Pipe::new();
+        register_node(kind, &[self.id()], &[output.id(), errors.id()]);
+        map_async(
+            self.subscribe(),
+            output.get_tx(),
+            errors.get_tx(),
+            options,
+            callback,
+        );
+        (output.to_rx_pipe(), errors.to_rx_pipe())
+    }
+
+    /// Map every value with an async callback.
+    ///
+    /// Returns the results, and a pipe of the calls that did not produce one, see [MapError].
+    pub fn map_async<U, Fut>(
+        &self,
+        options: AsyncOptions,
+        callback: impl Fn(T) -> Fut + Send + Sync + 'static,
+    ) -> (RxPipe<U>, RxPipe<MapError>)
+    where
+        U: Send + Clone + 'static,
+        Fut: Future<Output = U> + Send + 'static,
+    {
+        self.map_async_node("map_async", options, move |v| {
+            let call = callback(v);
+            async move { Ok(Some(call.await)) }
+        })
+    }
+
+    /// Map every value with an async callback, dropping values if it returns `None`.
+    ///
+    /// Returns the results, and a pipe of the calls that did not produce one, see [MapError].
+    pub fn filter_map_async<U, Fut>(
+        &self,
+        options: AsyncOptions,
+        callback: impl Fn(T) -> Fut + Send + Sync + 'static,
+    ) -> (RxPipe<U>, RxPipe<MapError>)
+    where
+        U: Send + Clone + 'static,
+        Fut: Future<Output = Option<U>> + Send + 'static,
+    {
+        self.map_async_node("filter_map_async", options, move |v| {
+            let call = callback(v);
+            async move { Ok(call.await) }
+        })
+    }
+
+    /// Map every value with an async callback that can fail.
+    ///
+    /// Returns the results, and a pipe of the calls that failed or timed out.
+    pub fn try_map<U, E, Fut>(
+        &self,
+        options: AsyncOptions,
+        callback: impl Fn(T) -> Fut + Send + Sync + 'static,
+    ) -> (RxPipe<U>, RxPipe<MapError<E>>)
+    where
+        U: Send + Clone + 'static,
+        E: Send + Clone + 'static,
+        Fut: Future<Output = Result<U, E>> + Send + 'static,
+    {
+        self.map_async_node("try_map", options, move |v| {
+            let call = callback(v);
+            async move { call.await.map(Some) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+
+    /// Sleep for `v` milliseconds, then fail if `v` is odd.
+    async fn slow(v: u64) -> Result<Option<u64>, String> {
+        time::sleep(ms(v)).await;
+        match v % 2 {
+            0 => Ok(Some(v)),
+            _ => Err(format!("{v} is odd")),
+        }
+    }
+
+    async fn assert_map_async(
+        options: AsyncOptions,
+        expected: &[(Duration, u64)],
+        expected_errors: &[(Duration, MapError<String>)],
+    ) {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let (err_tx, err_rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        let errors = PipeRecorder::new(&clock, err_rx);
+        map_async(in_rx, out_tx, err_tx, options, slow);
+
+        injector.script([(ms(0), 200), (ms(10), 50), (ms(20), 11)]);
+
+        recorder.assert_recorded_by(ms(500), expected).await;
+        errors.assert_recorded_by(ms(500), expected_errors).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_map_async_ordered() {
+        let options = AsyncOptions {
+            concurrency: 2,
+            ..AsyncOptions::default()
+        };
+        let expected = [(ms(200), 200), (ms(200), 50)];
+        let errors = [(ms(211), MapError::Failed("11 is odd".to_string()))];
+        assert_map_async(options, &expected, &errors).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_map_async_unordered() {
+        let options = AsyncOptions {
+            concurrency: 3,
+            ordered: false,
+            timeout: Some(ms(100)),
+        };
+        let expected = [(ms(60), 50)];
+        let errors = [
+            (ms(31), MapError::Failed("11 is odd".to_string())),
+            (ms(100), MapError::TimedOut(ms(100))),
+        ];
+        assert_map_async(options, &expected, &errors).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_map_async_restarts_after_panic() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
+        let (err_tx, _err_rx) = channel::<MapError>(10);
+        let callback = |v: i32| async move {
+            assert!(v != 0, "bad value");
+            Ok(Some(v + 1))
+        };
+        map_async(in_rx, out_tx, err_tx, AsyncOptions::default(), callback);
+
+        tx.send(0).await.unwrap();
+        tx.send(20).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 21);
+    }
+}
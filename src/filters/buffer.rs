@@ -0,0 +1,187 @@
+//! Collect values into batches.
+//!
+//! Values still in the buffer when the input is closed are sent as a final, smaller batch.
+use std::time::Duration;
+
+use tokio::select;
+use tokio::time::Instant;
+
+use super::timers::maybe_sleep_until;
+use crate::registry::register_node;
+use crate::{recv, send_or_log, spawn, Receiver, RxPipe, Sender};
+
+fn buffer_count<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<Vec<T>>,
+    count: usize,
+) {
+    spawn(async move {
+        let mut buffer = Vec::with_capacity(count);
+
+        while let Ok(v) = recv(&mut input).await {
+            buffer.push(v);
+            if buffer.len() >= count {
+                let values = std::mem::replace(&mut buffer, Vec::with_capacity(count));
+                send_or_log(&output, values).await;
+            }
+        }
+
+        if !buffer.is_empty() {
+            send_or_log(&output, buffer).await;
+        }
+    });
+}
+
+fn buffer_time<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<Vec<T>>,
+    duration: Duration,
+) {
+    spawn(async move {
+        let mut send_at: Option<Instant> = None;
+        let mut buffer = Vec::new();
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    if send_at.is_none() {
+                        send_at = Some(Instant::now() + duration);
+                    }
+                    buffer.push(v);
+                },
+                Some(()) = maybe_sleep_until(send_at) => {
+                    send_at = None;
+                    send_or_log(&output, std::mem::take(&mut buffer)).await;
+                },
+                else => { break; }
+            }
+        }
+
+        if !buffer.is_empty() {
+            send_or_log(&output, buffer).await;
+        }
+    });
+}
+
+fn buffer_until<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    mut trigger: Receiver<()>,
+    output: Sender<Vec<T>>,
+) {
+    spawn(async move {
+        let mut buffer = Vec::new();
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    buffer.push(v);
+                },
+                t = recv(&mut trigger) => {
+                    let Ok(()) = t else { break; };
+                    if !buffer.is_empty() {
+                        send_or_log(&output, std::mem::take(&mut buffer)).await;
+                    }
+                },
+            }
+        }
+
+        if !buffer.is_empty() {
+            send_or_log(&output, buffer).await;
+        }
+    });
+}
+
+impl<T: Send + Clone + 'static> RxPipe<T> {
+    /// Send the values in batches of `count`.
+    pub fn buffer_count(&self, count: usize) -> RxPipe<Vec<T>> {
+        assert!(count > 0, "buffer_count needs a count greater then 0");
+        let output = self.new_output();
+        register_node("buffer_count", &[self.id()], &[output.id()]);
+        buffer_count(self.subscribe(), output.get_tx(), count);
+        output.to_rx_pipe()
+    }
+
+    /// Send the values received within `duration` of the first one as one batch.
+    ///
+    /// Nothing is sent while no values arrive.
+    pub fn buffer_time(&self, duration: Duration) -> RxPipe<Vec<T>> {
+        let output = self.new_output();
+        register_node("buffer_time", &[self.id()], &[output.id()]);
+        buffer_time(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Send the values received so far as one batch whenever `trigger` sends a value.
+    ///
+    /// Nothing is sent if no values were received since the last batch.
+    pub fn buffer_until(&self, trigger: &RxPipe<()>) -> RxPipe<Vec<T>> {
+        let output = self.new_output();
+        register_node("buffer_until", &[self.id(), trigger.id()], &[output.id()]);
+        buffer_until(self.subscribe(), trigger.subscribe(), output.get_tx());
+        output.to_rx_pipe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+
+    #[tokio::test]
+    async fn test_buffer_count() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut out_rx) = channel(10);
+        buffer_count(in_rx, out_tx, 2);
+
+        for v in 1..=5 {
+            tx.send(v).await.unwrap();
+        }
+        assert_eq!(out_rx.recv().await.unwrap(), vec![1, 2]);
+        assert_eq!(out_rx.recv().await.unwrap(), vec![3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffer_time() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        buffer_time(in_rx, out_tx, ms(100));
+
+        injector.script([(ms(0), 1), (ms(50), 2), (ms(150), 3), (ms(300), 4)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(500),
+                &[
+                    (ms(100), vec![1, 2]),
+                    (ms(250), vec![3]),
+                    (ms(400), vec![4]),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffer_until() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (trigger_tx, trigger_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let trigger = Injector::new(&clock, trigger_tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        buffer_until(in_rx, trigger_rx, out_tx);
+
+        injector.script([(ms(0), 1), (ms(10), 2), (ms(200), 3)]);
+        trigger.script([(ms(100), ()), (ms(150), ()), (ms(250), ())]);
+
+        recorder
+            .assert_recorded_by(ms(300), &[(ms(100), vec![1, 2]), (ms(250), vec![3])])
+            .await;
+    }
+}
@@ -0,0 +1,144 @@
+//! Combine the latest values of several pipes.
+//!
+//! `combine_latestN` waits until every input has sent a value, then sends a tuple of the
+//! latest values whenever any input changes. `combine_latest_partialN` sends a tuple of
+//! `Option`s on every change, with `None` for inputs that have not sent anything yet.
+use tokio::select;
+
+use crate::registry::register_node;
+use crate::{recv, send_or_log, spawn, Pipe, Receiver, RxPipe, Sender};
+
+macro_rules! combine_latest {
+    ($combine:ident, $full:ident, $partial:ident, $($index:tt $input:ident: $T:ident),+) => {
+        #[allow(clippy::too_many_arguments)]
+        fn $combine<$($T: Send + Clone + 'static,)+ O: Send + Clone + 'static>(
+            $(mut $input: Receiver<$T>,)+
+            output: Sender<O>,
+            emit: impl Fn(($(Option<$T>,)+)) -> Option<O> + Send + 'static,
+        ) {
+            spawn(async move {
+                let mut latest: ($(Option<$T>,)+) = Default::default();
+
+                loop {
+                    select! {
+                        $(Ok(v) = recv(&mut $input) => { latest.$index = Some(v) },)+
+                        else => { break; }
+                    }
+
+                    if let Some(v) = emit(latest.clone()) {
+                        send_or_log(&output, v).await;
+                    }
+                }
+            });
+        }
+
+        /// Send the latest value of every input, once every input has sent a value.
+        pub fn $full<$($T: Send + Clone + 'static),+>(
+            $($input: RxPipe<$T>),+
+        ) -> RxPipe<($($T,)+)> {
+            let output = Pipe::new();
+            register_node("combine_latest", &[$($input.id()),+], &[output.id()]);
+            $combine($($input.subscribe(),)+ output.get_tx(), |latest| match latest {
+                ($(Some($input),)+) => Some(($($input,)+)),
+                _ => None,
+            });
+            output.to_rx_pipe()
+        }
+
+        /// Send the latest value of every input, or `None` if the input has not sent a value yet.
+        pub fn $partial<$($T: Send + Clone + 'static),+>(
+            $($input: RxPipe<$T>),+
+        ) -> RxPipe<($(Option<$T>,)+)> {
+            let output = Pipe::new();
+            register_node("combine_latest_partial", &[$($input.id()),+], &[output.id()]);
+            $combine($($input.subscribe(),)+ output.get_tx(), Some);
+            output.to_rx_pipe()
+        }
+    };
+}
+
+combine_latest!(combine2, combine_latest2, combine_latest_partial2, 0 a: A, 1 b: B);
+combine_latest!(
+    combine3,
+    combine_latest3,
+    combine_latest_partial3,
+    0 a: A,
+    1 b: B,
+    2 c: C
+);
+combine_latest!(
+    combine4,
+    combine_latest4,
+    combine_latest_partial4,
+    0 a: A,
+    1 b: B,
+    2 c: C,
+    3 d: D
+);
+combine_latest!(
+    combine5,
+    combine_latest5,
+    combine_latest_partial5,
+    0 a: A,
+    1 b: B,
+    2 c: C,
+    3 d: D,
+    4 e: E
+);
+combine_latest!(
+    combine6,
+    combine_latest6,
+    combine_latest_partial6,
+    0 a: A,
+    1 b: B,
+    2 c: C,
+    3 d: D,
+    4 e: E,
+    5 f: F
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+
+    #[tokio::test]
+    async fn test_combine_latest() {
+        let (a_tx, a_rx) = channel(10);
+        let (b_tx, b_rx) = channel(10);
+        let (out_tx, mut out_rx) = channel(10);
+        combine2(a_rx, b_rx, out_tx, |latest| match latest {
+            (Some(a), Some(b)) => Some((a, b)),
+            _ => None,
+        });
+
+        // Nothing is sent until both inputs have a value.
+        a_tx.send(1).await.unwrap();
+        b_tx.send("x").await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), (1, "x"));
+
+        a_tx.send(2).await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), (2, "x"));
+        b_tx.send("y").await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), (2, "y"));
+    }
+
+    #[tokio::test]
+    async fn test_combine_latest_partial() {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let c = Pipe::new();
+        let mut out_rx =
+            combine_latest_partial3(a.to_rx_pipe(), b.to_rx_pipe(), c.to_rx_pipe()).subscribe();
+
+        b.get_tx().send(true).await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), (None, Some(true), None));
+        a.get_tx().send(10).await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), (Some(10), Some(true), None));
+        c.get_tx().send("c").await.unwrap();
+        assert_eq!(
+            out_rx.recv().await.unwrap(),
+            (Some(10), Some(true), Some("c"))
+        );
+    }
+}
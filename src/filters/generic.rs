@@ -1,13 +1,20 @@
 //! Generic filter functions
-use crate::{recv, send_or_log, spawn, Pipe, RxPipe, TxPipe};
+use crate::persist;
+use crate::registry::{register_named_node, register_node};
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::{
+    recv, send_or_log, spawn, OverflowPolicy, Pipe, Receiver, RxPipe, Sender, TxPipe, PIPE_SIZE,
+};
+use futures::stream::{self, StreamExt};
 use log::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fmt::Debug;
-use tokio::{select, sync::broadcast};
+use std::sync::Arc;
+use tokio::select;
+use tokio::sync::Mutex;
 
-fn changed<T: Send + Eq + Clone + 'static>(
-    mut input: broadcast::Receiver<(Option<T>, T)>,
-    output: broadcast::Sender<T>,
-) {
+fn changed<T: Send + Eq + Clone + 'static>(mut input: Receiver<(Option<T>, T)>, output: Sender<T>) {
     spawn(async move {
         while let Ok(v) = recv(&mut input).await {
             let v = match v {
@@ -16,15 +23,15 @@ fn changed<T: Send + Eq + Clone + 'static>(
                 (_, new) => Some(new),
             };
             if let Some(v) = v {
-                send_or_log(&output, v);
+                send_or_log(&output, v).await;
             }
         }
     });
 }
 
 fn changed_or_unknown<T: Send + Eq + Clone + 'static>(
-    mut input: broadcast::Receiver<(Option<T>, T)>,
-    output: broadcast::Sender<T>,
+    mut input: Receiver<(Option<T>, T)>,
+    output: Sender<T>,
 ) {
     spawn(async move {
         while let Ok(v) = recv(&mut input).await {
@@ -34,121 +41,155 @@ fn changed_or_unknown<T: Send + Eq + Clone + 'static>(
                 (_, new) => Some(new),
             };
             if let Some(v) = v {
-                send_or_log(&output, v);
+                send_or_log(&output, v).await;
             }
         }
     });
 }
 
-fn diff<T: Send + Clone + 'static>(
-    input: broadcast::Receiver<T>,
-    output: broadcast::Sender<(Option<T>, T)>,
-) {
-    diff_with_initial_value(input, output, None)
+fn diff<T: Send + Clone + 'static>(input: Receiver<T>, output: Sender<(Option<T>, T)>) {
+    diff_with_initial_value(input, output, None, |_| {})
 }
 
 fn diff_with_initial_value<T: Send + Clone + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<(Option<T>, T)>,
+    mut input: Receiver<T>,
+    output: Sender<(Option<T>, T)>,
     initial_value: Option<T>,
+    save: impl Fn(&T) + Send + 'static,
 ) {
     spawn(async move {
         let mut old_value = initial_value;
         while let Ok(v) = recv(&mut input).await {
+            save(&v);
             let v_clone = v.clone();
-            send_or_log(&output, (old_value, v_clone));
+            send_or_log(&output, (old_value, v_clone)).await;
             old_value = Some(v);
         }
     });
 }
 
-fn map<T: Send + Clone + 'static, U: Send + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<U>,
-    callback: impl Send + 'static + Fn(T) -> U,
+fn map<T: Send + Clone + 'static, U: Send + Clone + 'static>(
+    input: Receiver<T>,
+    output: Sender<U>,
+    callback: impl Send + Sync + 'static + Fn(T) -> U,
 ) {
-    spawn(async move {
-        while let Ok(v) = recv(&mut input).await {
-            let v = callback(v);
-            send_or_log(&output, v);
+    let input = Arc::new(Mutex::new(input));
+    let callback = Arc::new(callback);
+    supervise("map", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let callback = callback.clone();
+        async move {
+            let mut input = input.lock().await;
+            while let Ok(v) = recv(&mut input).await {
+                let v = callback(v);
+                send_or_log(&output, v).await;
+            }
         }
     });
 }
 
-fn map_with_state<T: Send + Clone + 'static, U: Send + 'static, V: Send + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<U>,
+fn map_with_state<T: Send + Clone + 'static, U: Send + Clone + 'static, V: Send + 'static>(
+    input: Receiver<T>,
+    output: Sender<U>,
     initial: V,
-    callback: impl Send + 'static + Fn(&mut V, T) -> U,
+    callback: impl Send + Sync + 'static + Fn(&mut V, T) -> U,
+    save: impl Fn(&V) + Send + Sync + 'static,
 ) {
-    let mut state: V = initial;
-    spawn(async move {
-        while let Ok(v) = recv(&mut input).await {
-            let v = callback(&mut state, v);
-            send_or_log(&output, v);
+    let input = Arc::new(Mutex::new(input));
+    let state = Arc::new(Mutex::new(initial));
+    let callback = Arc::new(callback);
+    let save = Arc::new(save);
+    supervise("map_with_state", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let state = state.clone();
+        let callback = callback.clone();
+        let save = save.clone();
+        async move {
+            let mut input = input.lock().await;
+            let mut state = state.lock().await;
+            while let Ok(v) = recv(&mut input).await {
+                let v = callback(&mut state, v);
+                save(&state);
+                send_or_log(&output, v).await;
+            }
         }
     });
 }
 
 fn debug<T: Send + Clone + core::fmt::Debug + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<T>,
+    mut input: Receiver<T>,
+    output: Sender<T>,
     msg: &str,
 ) {
     let msg = msg.to_string();
     spawn(async move {
         while let Ok(v) = recv(&mut input).await {
             debug!("debug {msg} {v:?}");
-            send_or_log(&output, v);
+            send_or_log(&output, v).await;
         }
     });
 }
 
-fn filter_map<T: Send + Clone + 'static, U: Send + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<U>,
-    callback: impl Send + 'static + Fn(T) -> Option<U>,
+fn filter_map<T: Send + Clone + 'static, U: Send + Clone + 'static>(
+    input: Receiver<T>,
+    output: Sender<U>,
+    callback: impl Send + Sync + 'static + Fn(T) -> Option<U>,
 ) {
-    spawn(async move {
-        while let Ok(v) = recv(&mut input).await {
-            let filter = callback(v);
-            if let Some(v) = filter {
-                send_or_log(&output, v);
+    let input = Arc::new(Mutex::new(input));
+    let callback = Arc::new(callback);
+    supervise("filter_map", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let callback = callback.clone();
+        async move {
+            let mut input = input.lock().await;
+            while let Ok(v) = recv(&mut input).await {
+                let filter = callback(v);
+                if let Some(v) = filter {
+                    send_or_log(&output, v).await;
+                }
             }
         }
     });
 }
 
-fn copy<T: Send + Clone + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<T>,
-) {
+fn copy<T: Send + Clone + 'static>(mut input: Receiver<T>, output: Sender<T>) {
     spawn(async move {
         while let Ok(v) = recv(&mut input).await {
-            send_or_log(&output, v);
+            send_or_log(&output, v).await;
         }
     });
 }
 
 fn filter<T: Send + Clone + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<T>,
-    callback: impl Send + 'static + Fn(&T) -> bool,
+    input: Receiver<T>,
+    output: Sender<T>,
+    callback: impl Send + Sync + 'static + Fn(&T) -> bool,
 ) {
-    spawn(async move {
-        while let Ok(v) = recv(&mut input).await {
-            let filter = callback(&v);
-            if filter {
-                send_or_log(&output, v);
+    let input = Arc::new(Mutex::new(input));
+    let callback = Arc::new(callback);
+    supervise("filter", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let callback = callback.clone();
+        async move {
+            let mut input = input.lock().await;
+            while let Ok(v) = recv(&mut input).await {
+                let filter = callback(&v);
+                if filter {
+                    send_or_log(&output, v).await;
+                }
             }
         }
     });
 }
 
 fn gate<T: Send + Clone + 'static>(
-    mut input: broadcast::Receiver<T>,
-    mut gate: broadcast::Receiver<bool>,
-    output: broadcast::Sender<T>,
+    mut input: Receiver<T>,
+    mut gate: Receiver<bool>,
+    output: Sender<T>,
 ) {
     spawn(async move {
         let mut filter = true;
@@ -161,7 +202,7 @@ fn gate<T: Send + Clone + 'static>(
                 }
                 Ok(input) = recv(&mut input) => {
                     if filter {
-                        send_or_log(&output, input);
+                        send_or_log(&output, input).await;
                     }
                 }
                 else => { break; }
@@ -171,10 +212,10 @@ fn gate<T: Send + Clone + 'static>(
 }
 
 fn _if_else<T: Send + Clone + 'static>(
-    mut gate: broadcast::Receiver<bool>,
-    mut if_true: broadcast::Receiver<T>,
-    mut if_false: broadcast::Receiver<T>,
-    output: broadcast::Sender<T>,
+    mut gate: Receiver<bool>,
+    mut if_true: Receiver<T>,
+    mut if_false: Receiver<T>,
+    output: Sender<T>,
 ) {
     spawn(async move {
         let mut filter: Option<bool> = None;
@@ -200,7 +241,7 @@ fn _if_else<T: Send + Clone + 'static>(
                 None => &None,
             };
             if let Some(v) = value {
-                send_or_log(&output, v.clone());
+                send_or_log(&output, v.clone()).await;
             }
         }
     });
@@ -213,6 +254,11 @@ pub fn if_else<T: Send + Clone + 'static>(
     if_false: RxPipe<T>,
 ) -> RxPipe<T> {
     let output = Pipe::new();
+    register_node(
+        "if_else",
+        &[gate.id(), if_true.id(), if_false.id()],
+        &[output.id()],
+    );
     _if_else(
         gate.subscribe(),
         if_true.subscribe(),
@@ -222,12 +268,64 @@ pub fn if_else<T: Send + Clone + 'static>(
     output.to_rx_pipe()
 }
 
+/// The position of an input in the list given to [merge_tagged].
+pub type SourceIndex = usize;
+
+fn _merge<T: Send + Clone + 'static, U: Send + Clone + 'static>(
+    inputs: Vec<Receiver<T>>,
+    output: Sender<U>,
+    tag: fn(SourceIndex, T) -> U,
+) {
+    spawn(async move {
+        let inputs = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(index, input)| input.into_stream().map(move |v| (index, v)));
+        let mut merged = stream::select_all(inputs);
+
+        while let Some((index, v)) = merged.next().await {
+            send_or_log(&output, tag(index, v)).await;
+        }
+    });
+}
+
+fn merge_inputs<T: Send + Clone + 'static, U: Send + Clone + 'static>(
+    kind: &str,
+    inputs: Vec<RxPipe<T>>,
+    tag: fn(SourceIndex, T) -> U,
+) -> RxPipe<U> {
+    let output = Pipe::new();
+    let ids: Vec<_> = inputs.iter().map(|input| input.id()).collect();
+    register_node(kind, &ids, &[output.id()]);
+    // With no inputs there is nothing to wait for, the output is closed straight away.
+    if !inputs.is_empty() {
+        let inputs = inputs.iter().map(|input| input.subscribe()).collect();
+        _merge(inputs, output.get_tx(), tag);
+    }
+    output.to_rx_pipe()
+}
+
+/// Pass through the values from all inputs.
+///
+/// The output is closed once every input is closed.
+pub fn merge<T: Send + Clone + 'static>(inputs: Vec<RxPipe<T>>) -> RxPipe<T> {
+    merge_inputs("merge", inputs, |_, v| v)
+}
+
+/// Pass through the values from all inputs, along with the index of the input they came from.
+///
+/// The output is closed once every input is closed.
+pub fn merge_tagged<T: Send + Clone + 'static>(inputs: Vec<RxPipe<T>>) -> RxPipe<(SourceIndex, T)> {
+    merge_inputs("merge_tagged", inputs, |index, v| (index, v))
+}
+
 impl<T: Send + Clone + 'static> RxPipe<T> {
     /// Add previous value to the input stream.
     ///
     /// If there was no previous value, then add None.
-    pub fn diff(&mut self) -> RxPipe<(Option<T>, T)> {
-        let output = Pipe::new();
+    pub fn diff(&self) -> RxPipe<(Option<T>, T)> {
+        let output = self.new_output();
+        register_node("diff", &[self.id()], &[output.id()]);
         diff(self.subscribe(), output.get_tx());
         output.to_rx_pipe()
     }
@@ -235,9 +333,29 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     /// Add previous value to the input stream.
     ///
     /// If there was no previous value, then add the initial value.
-    pub fn diff_with_initial_value(&mut self, initial_value: Option<T>) -> RxPipe<(Option<T>, T)> {
-        let output = Pipe::new();
-        diff_with_initial_value(self.subscribe(), output.get_tx(), initial_value);
+    pub fn diff_with_initial_value(&self, initial_value: Option<T>) -> RxPipe<(Option<T>, T)> {
+        let output = self.new_output();
+        register_node("diff_with_initial_value", &[self.id()], &[output.id()]);
+        diff_with_initial_value(self.subscribe(), output.get_tx(), initial_value, |_| {});
+        output.to_rx_pipe()
+    }
+}
+
+impl<T: Send + Clone + Serialize + DeserializeOwned + 'static> RxPipe<T> {
+    /// Like [Self::diff_with_initial_value], but the previous value survives a restart.
+    ///
+    /// The last value is persisted under `id`, see [persist]. The initial value is only used if
+    /// nothing was persisted.
+    pub fn diff_persisted(&self, id: &str, initial_value: Option<T>) -> RxPipe<(Option<T>, T)> {
+        let output = self.new_output();
+        register_named_node("diff", id, &[self.id()], &[output.id()]);
+        persist::claim(id);
+
+        let initial_value = persist::load(id).or(initial_value);
+        let id = id.to_string();
+        diff_with_initial_value(self.subscribe(), output.get_tx(), initial_value, move |v| {
+            persist::save(&id, v)
+        });
         output.to_rx_pipe()
     }
 }
@@ -245,14 +363,16 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
 impl<T: Send + Eq + Clone + 'static> RxPipe<(Option<T>, T)> {
     /// Has the stream from [Self::diff] or [Self::diff_with_initial_value] changed?
     pub fn changed(&self) -> RxPipe<T> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("changed", &[self.id()], &[output.id()]);
         changed(self.subscribe(), output.get_tx());
         output.to_rx_pipe()
     }
 
     /// Has the stream from [Self::diff] or [Self::diff_with_initial_value] changed or was previous value unknown?
     pub fn changed_or_unknown(&self) -> RxPipe<T> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("changed_or_unknown", &[self.id()], &[output.id()]);
         changed_or_unknown(self.subscribe(), output.get_tx());
         output.to_rx_pipe()
     }
@@ -261,7 +381,8 @@ impl<T: Send + Eq + Clone + 'static> RxPipe<(Option<T>, T)> {
 impl<T: Send + Debug + Clone + 'static> RxPipe<T> {
     /// Log the value and pass through unchanged.
     pub fn debug(&self, msg: &str) -> RxPipe<T> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_named_node("debug", msg, &[self.id()], &[output.id()]);
         debug(self.subscribe(), output.get_tx(), msg);
         output.to_rx_pipe()
     }
@@ -269,11 +390,14 @@ impl<T: Send + Debug + Clone + 'static> RxPipe<T> {
 
 impl<T: Send + Clone + 'static> RxPipe<T> {
     /// Map value through function and (optionally) change its type.
+    ///
+    /// If the function panics the task is restarted and processing continues with the next value.
     pub fn map<U: Send + Clone + 'static>(
         &self,
-        callback: impl Send + 'static + Fn(T) -> U,
+        callback: impl Send + Sync + 'static + Fn(T) -> U,
     ) -> RxPipe<U> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("map", &[self.id()], &[output.id()]);
         map(self.subscribe(), output.get_tx(), callback);
         output.to_rx_pipe()
     }
@@ -285,10 +409,40 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     pub fn map_with_state<U: Send + Clone + 'static, V: Send + 'static>(
         &self,
         initial: V,
-        callback: impl Send + 'static + Fn(&mut V, T) -> U,
+        callback: impl Send + Sync + 'static + Fn(&mut V, T) -> U,
+    ) -> RxPipe<U> {
+        let output = self.new_output();
+        register_node("map_with_state", &[self.id()], &[output.id()]);
+        map_with_state(self.subscribe(), output.get_tx(), initial, callback, |_| {});
+        output.to_rx_pipe()
+    }
+
+    /// Like [Self::map_with_state], but the state survives a restart.
+    ///
+    /// The state is persisted under `id` after every call, see [persist]. The initial value is
+    /// only used if nothing was persisted.
+    pub fn map_with_state_persisted<
+        U: Send + Clone + 'static,
+        V: Send + Clone + Serialize + DeserializeOwned + 'static,
+    >(
+        &self,
+        id: &str,
+        initial: V,
+        callback: impl Send + Sync + 'static + Fn(&mut V, T) -> U,
     ) -> RxPipe<U> {
-        let output = Pipe::new();
-        map_with_state(self.subscribe(), output.get_tx(), initial, callback);
+        let output = self.new_output();
+        register_named_node("map_with_state", id, &[self.id()], &[output.id()]);
+        persist::claim(id);
+
+        let initial = persist::load(id).unwrap_or(initial);
+        let id = id.to_string();
+        map_with_state(
+            self.subscribe(),
+            output.get_tx(),
+            initial,
+            callback,
+            move |state| persist::save(&id, state),
+        );
         output.to_rx_pipe()
     }
 
@@ -297,9 +451,10 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     /// Unlike with [Self::map], if the function returns None, the value is dropped.
     pub fn filter_map<U: Send + Clone + 'static>(
         &self,
-        callback: impl Send + 'static + Fn(T) -> Option<U>,
+        callback: impl Send + Sync + 'static + Fn(T) -> Option<U>,
     ) -> RxPipe<U> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("filter_map", &[self.id()], &[output.id()]);
         filter_map(self.subscribe(), output.get_tx(), callback);
         output.to_rx_pipe()
     }
@@ -308,8 +463,9 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     ///
     /// This function always returns the same data. If the function returns True the value is transmitted,
     /// otherwise the value is dropped.
-    pub fn filter(&self, callback: impl Send + 'static + Fn(&T) -> bool) -> RxPipe<T> {
-        let output = Pipe::new();
+    pub fn filter(&self, callback: impl Send + Sync + 'static + Fn(&T) -> bool) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("filter", &[self.id()], &[output.id()]);
         filter(self.subscribe(), output.get_tx(), callback);
         output.to_rx_pipe()
     }
@@ -318,197 +474,338 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     ///
     /// Similar to [Self::filter], but the allow value comes from an RxPipe.
     pub fn gate(&self, allow: RxPipe<bool>) -> RxPipe<T> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("gate", &[self.id(), allow.id()], &[output.id()]);
         gate(self.subscribe(), allow.subscribe(), output.get_tx());
         output.to_rx_pipe()
     }
 
     /// Pass all values on to a [TxPipe].
     pub fn copy_to(&self, output: &TxPipe<T>) {
+        register_node("copy_to", &[self.id()], &[output.id()]);
+        copy(self.subscribe(), output.get_tx());
+    }
+
+    /// Pass all values on to a pipe with a different [OverflowPolicy].
+    ///
+    /// Operators use the policy of their input for their output, so everything after this
+    /// uses `policy` as well. Use it early in a flow, any pipe before it keeps its own policy.
+    pub fn with_policy(&self, policy: OverflowPolicy) -> RxPipe<T> {
+        let output = Pipe::new_with_policy(PIPE_SIZE, policy);
+        register_node("with_policy", &[self.id()], &[output.id()]);
         copy(self.subscribe(), output.get_tx());
+        output.to_rx_pipe()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+    use tokio::sync::broadcast::error::RecvError;
 
     #[tokio::test]
     async fn test_diff() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         diff(in_rx, out_tx);
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, (None, 10));
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, (Some(10), 10));
 
-        tx.send(20).unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, (Some(10), 20));
     }
 
     #[tokio::test]
     async fn test_changed() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         changed(in_rx, out_tx);
 
-        tx.send((None, 10)).unwrap();
+        tx.send((None, 10)).await.unwrap();
 
-        tx.send((Some(10), 20)).unwrap();
+        tx.send((Some(10), 20)).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 20);
 
-        tx.send((Some(20), 20)).unwrap();
+        tx.send((Some(20), 20)).await.unwrap();
 
-        tx.send((Some(20), 30)).unwrap();
+        tx.send((Some(20), 30)).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 30);
     }
 
     #[tokio::test]
     async fn test_changed_or_unknown() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         changed_or_unknown(in_rx, out_tx);
 
-        tx.send((None, 10)).unwrap();
+        tx.send((None, 10)).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 10);
 
-        tx.send((Some(10), 20)).unwrap();
+        tx.send((Some(10), 20)).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 20);
 
-        tx.send((Some(20), 20)).unwrap();
+        tx.send((Some(20), 20)).await.unwrap();
 
-        tx.send((Some(20), 30)).unwrap();
+        tx.send((Some(20), 30)).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 30);
     }
 
     #[tokio::test]
     async fn test_map() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         map(in_rx, out_tx, |x| x + 1);
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 11);
 
-        tx.send(20).unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 21);
     }
 
     #[tokio::test]
-    async fn test_map_with_state() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
-        map_with_state(in_rx, out_tx, 3, |state, x| {
-            *state += 1;
-            x + *state
+    async fn test_map_closes_output() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
+        map(in_rx, out_tx, |x| x + 1);
+
+        tx.send(10).await.unwrap();
+        drop(tx);
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, 11);
+
+        let v = rx.recv().await;
+        assert!(matches!(v, Err(RecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_map_restarts_after_panic() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
+        map(in_rx, out_tx, |x: i32| {
+            assert!(x != 0, "bad value");
+            x + 1
         });
 
-        tx.send(10).unwrap();
+        tx.send(0).await.unwrap();
+        tx.send(20).await.unwrap();
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, 21);
+    }
+
+    #[tokio::test]
+    async fn test_map_with_state() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
+        map_with_state(
+            in_rx,
+            out_tx,
+            3,
+            |state, x| {
+                *state += 1;
+                x + *state
+            },
+            |_| {},
+        );
+
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 14);
 
-        tx.send(20).unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 25);
     }
 
+    #[tokio::test]
+    async fn test_map_with_state_persisted() {
+        let id = "test_map_with_state_persisted";
+        persist::save(id, &5);
+
+        let input = Pipe::new();
+        let mut rx = input
+            .to_rx_pipe()
+            .map_with_state_persisted(id, 3, |state: &mut i32, x: i32| {
+                *state += 1;
+                x + *state
+            })
+            .subscribe();
+
+        input.get_tx().send(10).await.unwrap();
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, 16);
+        assert_eq!(persist::load::<i32>(id), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_diff_persisted() {
+        let id = "test_diff_persisted";
+        persist::save(id, &10);
+
+        let input = Pipe::new();
+        let mut rx = input.to_rx_pipe().diff_persisted(id, None).subscribe();
+
+        input.get_tx().send(20).await.unwrap();
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, (Some(10), 20));
+        assert_eq!(persist::load::<i32>(id), Some(20));
+    }
+
     #[tokio::test]
     async fn test_debug() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         debug(in_rx, out_tx, "message");
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 10);
 
-        tx.send(20).unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 20);
     }
 
     #[tokio::test]
     async fn test_filter_map() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         filter_map(in_rx, out_tx, |v| if v > 10 { Some(v + 1) } else { None });
 
-        tx.send(10).unwrap();
-        tx.send(10).unwrap();
-        tx.send(20).unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 21);
     }
 
     #[tokio::test]
     async fn test_copy() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         copy(in_rx, out_tx);
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 10);
 
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 10);
 
-        tx.send(20).unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 20);
     }
 
     #[tokio::test]
     async fn test_filter() {
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
         filter(in_rx, out_tx, |&v| v > 10);
 
-        tx.send(10).unwrap();
-        tx.send(10).unwrap();
-        tx.send(20).unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(20).await.unwrap();
         let v = rx.recv().await.unwrap();
         assert_eq!(v, 20);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_gate() {
-        // FIXME: This test is awful
-        // Sleep required to try to force gate to process messages in correct order.
-        let (gate_tx, gate_rx) = broadcast::channel(10);
-
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
+        let clock = TestClock::new();
+        let (gate_tx, gate_rx) = channel(10);
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let gate_injector = Injector::new(&clock, gate_tx);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
 
         gate(in_rx, gate_rx, out_tx);
 
-        tx.send(10).unwrap();
-        let v = rx.recv().await.unwrap();
-        assert_eq!(v, 10);
+        injector.script([(ms(0), 10), (ms(200), 20), (ms(400), 30)]);
+        gate_injector.script([(ms(100), false), (ms(300), true)]);
 
-        gate_tx.send(false).unwrap();
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        tx.send(20).unwrap();
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        recorder.assert_values_by(ms(500), &[10, 30]).await;
+    }
 
-        gate_tx.send(true).unwrap();
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        tx.send(30).unwrap();
-        let v = rx.recv().await.unwrap();
-        assert_eq!(v, 30);
+    #[tokio::test]
+    async fn test_with_policy() {
+        let input = Pipe::new();
+        let output = input
+            .to_rx_pipe()
+            .with_policy(OverflowPolicy::Backpressure)
+            .map(|v: i32| v + 1);
+        let mut rx = output.subscribe();
+        assert_eq!(output.policy(), OverflowPolicy::Backpressure);
+        assert_eq!(
+            input.to_rx_pipe().map(|v| v).policy(),
+            OverflowPolicy::DropOldest
+        );
+
+        // The input drops the oldest values, so give it time to pass each one on.
+        let tx = input.get_tx();
+        for v in 0..20 {
+            tx.send(v).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        for v in 0..20 {
+            assert_eq!(rx.recv().await.unwrap(), v + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let mut rx = merge(vec![a.to_rx_pipe(), b.to_rx_pipe()]).subscribe();
+        let a_tx = a.get_tx();
+        let b_tx = b.get_tx();
+
+        a_tx.send(1).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 1);
+        b_tx.send(2).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 2);
+
+        // Closing one input does not close the output. Closing all of them does, but that
+        // stops the task, so it is tested during shutdown in tests/merge.rs.
+        drop(a);
+        drop(a_tx);
+        b_tx.send(3).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_merge_tagged() {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let mut rx = merge_tagged(vec![a.to_rx_pipe(), b.to_rx_pipe()]).subscribe();
+
+        b.get_tx().send("b").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), (1, "b"));
+        a.get_tx().send("a").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), (0, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_nothing() {
+        let mut rx = merge::<i32>(vec![]).subscribe();
+        assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
     }
 }
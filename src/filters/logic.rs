@@ -0,0 +1,235 @@
+//! Boolean logic on pipes of booleans.
+//!
+//! Like [combine_latest](super::combine), the result is sent whenever any input sends a
+//! value. [Unknown] decides what happens before every input has sent a value.
+use super::generic::merge_tagged;
+use crate::RxPipe;
+
+/// How inputs that have not sent a value yet are treated.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Unknown {
+    /// Treat them as false.
+    False,
+    /// Treat them as true.
+    True,
+    /// Send nothing until every input has sent a value.
+    Withhold,
+}
+
+impl Unknown {
+    fn resolve(self, latest: &[Option<bool>]) -> Option<Vec<bool>> {
+        latest
+            .iter()
+            .map(|v| match (v, self) {
+                (Some(v), _) => Some(*v),
+                (None, Unknown::False) => Some(false),
+                (None, Unknown::True) => Some(true),
+                (None, Unknown::Withhold) => None,
+            })
+            .collect()
+    }
+}
+
+fn logic(
+    kind: &str,
+    inputs: Vec<RxPipe<bool>>,
+    unknown: Unknown,
+    op: fn(&[bool]) -> bool,
+) -> RxPipe<bool> {
+    let latest = vec![None; inputs.len()];
+    merge_tagged(inputs)
+        .map_with_state(latest, move |latest, (index, v)| {
+            latest[index] = Some(v);
+            unknown.resolve(latest).map(|values| op(&values))
+        })
+        .filter_map(|v| v)
+        .named(kind)
+}
+
+fn all_true(values: &[bool]) -> bool {
+    values.iter().all(|v| *v)
+}
+
+fn any_true(values: &[bool]) -> bool {
+    values.iter().any(|v| *v)
+}
+
+/// A transition of a pipe of booleans.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Edge {
+    /// From false to true.
+    Rising,
+    /// From true to false.
+    Falling,
+}
+
+impl Edge {
+    fn from_diff(diff: (Option<bool>, bool)) -> Option<Edge> {
+        match diff {
+            (Some(false), true) => Some(Edge::Rising),
+            (Some(true), false) => Some(Edge::Falling),
+            _ => None,
+        }
+    }
+}
+
+/// Send true when `set` sends a value, and false when `reset` sends a value.
+///
+/// The state is sent after every value, nothing is sent before the first one.
+pub fn latch(set: RxPipe<()>, reset: RxPipe<()>) -> RxPipe<bool> {
+    merge_tagged(vec![set, reset])
+        .map(|(index, ())| index == 0)
+        .named("latch")
+}
+
+/// Send true if every input is true.
+pub fn all(inputs: Vec<RxPipe<bool>>, unknown: Unknown) -> RxPipe<bool> {
+    logic("all", inputs, unknown, all_true)
+}
+
+/// Send true if any input is true.
+pub fn any(inputs: Vec<RxPipe<bool>>, unknown: Unknown) -> RxPipe<bool> {
+    logic("any", inputs, unknown, any_true)
+}
+
+impl RxPipe<bool> {
+    /// Send true if both this pipe and `other` are true.
+    pub fn and(&self, other: &RxPipe<bool>, unknown: Unknown) -> RxPipe<bool> {
+        logic("and", vec![self.clone(), other.clone()], unknown, all_true)
+    }
+
+    /// Send true if this pipe or `other` is true.
+    pub fn or(&self, other: &RxPipe<bool>, unknown: Unknown) -> RxPipe<bool> {
+        logic("or", vec![self.clone(), other.clone()], unknown, any_true)
+    }
+
+    /// Send true if exactly one of this pipe and `other` is true.
+    pub fn xor(&self, other: &RxPipe<bool>, unknown: Unknown) -> RxPipe<bool> {
+        logic(
+            "xor",
+            vec![self.clone(), other.clone()],
+            unknown,
+            |values| values[0] != values[1],
+        )
+    }
+
+    /// Send the opposite of every value.
+    pub fn not(&self) -> RxPipe<bool> {
+        self.map(|v| !v).named("not")
+    }
+
+    /// Send every transition.
+    ///
+    /// The first value is not a transition, as the previous value is unknown.
+    pub fn edges(&self) -> RxPipe<Edge> {
+        self.diff().filter_map(Edge::from_diff).named("edges")
+    }
+
+    /// Send a value every time this pipe goes from false to true.
+    pub fn rising_edge(&self) -> RxPipe<()> {
+        self.edges()
+            .filter_map(|edge| (edge == Edge::Rising).then_some(()))
+            .named("rising_edge")
+    }
+
+    /// Send a value every time this pipe goes from true to false.
+    pub fn falling_edge(&self) -> RxPipe<()> {
+        self.edges()
+            .filter_map(|edge| (edge == Edge::Falling).then_some(()))
+            .named("falling_edge")
+    }
+}
+
+impl RxPipe<()> {
+    /// Flip between true and false every time a value is received, starting with `!initial`.
+    pub fn toggle(&self, initial: bool) -> RxPipe<bool> {
+        self.map_with_state(initial, |state, ()| {
+            *state = !*state;
+            *state
+        })
+        .named("toggle")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pipe;
+
+    #[test]
+    fn test_unknown() {
+        let latest = [Some(true), None];
+        assert_eq!(Unknown::False.resolve(&latest), Some(vec![true, false]));
+        assert_eq!(Unknown::True.resolve(&latest), Some(vec![true, true]));
+        assert_eq!(Unknown::Withhold.resolve(&latest), None);
+    }
+
+    #[tokio::test]
+    async fn test_and() {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let mut out_rx = a
+            .to_rx_pipe()
+            .and(&b.to_rx_pipe(), Unknown::Withhold)
+            .subscribe();
+
+        a.get_tx().send(true).await.unwrap();
+        b.get_tx().send(false).await.unwrap();
+        assert!(!out_rx.recv().await.unwrap());
+        b.get_tx().send(true).await.unwrap();
+        assert!(out_rx.recv().await.unwrap());
+        a.get_tx().send(false).await.unwrap();
+        assert!(!out_rx.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_any_unknown_true() {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let c = Pipe::new();
+        let inputs = vec![a.to_rx_pipe(), b.to_rx_pipe(), c.to_rx_pipe()];
+        let mut out_rx = any(inputs, Unknown::True).subscribe();
+
+        a.get_tx().send(false).await.unwrap();
+        assert!(out_rx.recv().await.unwrap());
+        b.get_tx().send(false).await.unwrap();
+        assert!(out_rx.recv().await.unwrap());
+        c.get_tx().send(false).await.unwrap();
+        assert!(!out_rx.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_edges() {
+        let input = Pipe::new();
+        let rx_pipe = input.to_rx_pipe();
+        let mut edges = rx_pipe.edges().subscribe();
+        let mut rising = rx_pipe.rising_edge().subscribe();
+        let tx = input.get_tx();
+
+        for v in [true, true, false, true, false, false] {
+            tx.send(v).await.unwrap();
+        }
+        assert_eq!(edges.recv().await.unwrap(), Edge::Falling);
+        assert_eq!(edges.recv().await.unwrap(), Edge::Rising);
+        assert_eq!(edges.recv().await.unwrap(), Edge::Falling);
+        rising.recv().await.unwrap();
+        assert!(rising.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_latch_and_toggle() {
+        let set = Pipe::new();
+        let reset = Pipe::new();
+        let mut latched = latch(set.to_rx_pipe(), reset.to_rx_pipe()).subscribe();
+        let mut toggled = set.to_rx_pipe().toggle(false).subscribe();
+
+        set.get_tx().send(()).await.unwrap();
+        assert!(latched.recv().await.unwrap());
+        assert!(toggled.recv().await.unwrap());
+        reset.get_tx().send(()).await.unwrap();
+        assert!(!latched.recv().await.unwrap());
+        set.get_tx().send(()).await.unwrap();
+        assert!(latched.recv().await.unwrap());
+        assert!(!toggled.recv().await.unwrap());
+    }
+}
@@ -1,4 +1,11 @@
 //! Filters that take one/more inputs and produce out output.
+pub mod aggregate;
+pub mod async_map;
+pub mod buffer;
+pub mod combine;
 pub mod generic;
+pub mod logic;
+pub mod reminder;
 pub mod teslamate;
+pub mod threshold;
 pub mod timers;
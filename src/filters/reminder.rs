@@ -0,0 +1,304 @@
+//! Repeat reminders while a condition is true, backing off and keeping quiet at night.
+use std::future;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+use super::timers::maybe_sleep_until;
+use crate::registry::register_node;
+use crate::{recv, send_or_log, spawn, Receiver, RxPipe, Sender};
+
+/// A daily window in which reminders are not sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    /// Local time the quiet hours start.
+    pub start: NaiveTime,
+    /// Local time the quiet hours end, this may be before `start` to span midnight.
+    pub end: NaiveTime,
+    /// The timezone of `start` and `end`.
+    pub timezone: Tz,
+}
+
+impl QuietHours {
+    /// Get how long until the quiet hours end, or `None` if `now` is not in quiet hours.
+    pub fn remaining(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let local = now.with_timezone(&self.timezone).naive_local();
+        let time = local.time();
+        let quiet = if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        };
+        if !quiet {
+            return None;
+        }
+
+        let date = if time < self.end {
+            local.date()
+        } else {
+            local.date().succ()
+        };
+        // Resolve the end in the timezone, as quiet hours that span a DST change are an hour
+        // longer or shorter. If the end is skipped by the change, they end an hour later.
+        let end = date.and_time(self.end);
+        let end = match self.timezone.from_local_datetime(&end).earliest() {
+            Some(end) => end,
+            None => self
+                .timezone
+                .from_local_datetime(&(end + chrono::Duration::hours(1)))
+                .earliest()?,
+        };
+        (end.with_timezone(&Utc) - now).to_std().ok()
+    }
+}
+
+/// How [RxPipe::reminder] repeats reminders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReminderPolicy {
+    /// The time before each repeat, the last one is used for all later repeats.
+    pub intervals: Vec<Duration>,
+    /// Stop after this many repeats, or never stop if `None`.
+    pub max_repeats: Option<usize>,
+    /// Reminders due in quiet hours are sent when they end, as is the false that clears them.
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl ReminderPolicy {
+    /// Repeat every `interval` until the condition clears.
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            intervals: vec![interval],
+            max_repeats: None,
+            quiet_hours: None,
+        }
+    }
+
+    /// Get the time until the next reminder, after `sent` reminders.
+    fn next_interval(&self, sent: usize) -> Option<Duration> {
+        let repeats = sent.saturating_sub(1);
+        if self.max_repeats.is_some_and(|max| repeats >= max) {
+            return None;
+        }
+        let index = repeats.min(self.intervals.len().checked_sub(1)?);
+        self.intervals.get(index).copied()
+    }
+}
+
+async fn maybe_recv<T: Clone>(rx: &mut Option<Receiver<T>>) -> Result<T, RecvError> {
+    match rx {
+        Some(rx) => recv(rx).await,
+        None => future::pending().await,
+    }
+}
+
+fn reminder(
+    mut input: Receiver<bool>,
+    mut snooze: Option<Receiver<Duration>>,
+    output: Sender<bool>,
+    policy: ReminderPolicy,
+    now: impl Fn() -> DateTime<Utc> + Send + 'static,
+) {
+    spawn(async move {
+        let mut next: Option<Instant> = None;
+        let mut sent: usize = 0;
+        let mut active = false;
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    if v && !active {
+                        active = true;
+                        sent = 0;
+                        next = Some(Instant::now());
+                    } else if !v && active {
+                        active = false;
+                        // Only clear reminders that were heard, once quiet hours are over.
+                        next = (sent > 0).then(Instant::now);
+                    }
+                },
+                v = maybe_recv(&mut snooze) => {
+                    let Ok(duration) = v else {
+                        snooze = None;
+                        continue;
+                    };
+                    if active {
+                        let until = Instant::now() + duration;
+                        next = next.map(|next| next.max(until));
+                    }
+                },
+                Some(()) = maybe_sleep_until(next) => {
+                    let quiet = policy.quiet_hours.as_ref().and_then(|q| q.remaining(now()));
+                    if let Some(remaining) = quiet {
+                        next = Some(Instant::now() + remaining);
+                    } else if !active {
+                        sent = 0;
+                        next = None;
+                        send_or_log(&output, false).await;
+                    } else {
+                        sent += 1;
+                        next = policy.next_interval(sent).map(|d| Instant::now() + d);
+                        send_or_log(&output, true).await;
+                    }
+                },
+            }
+        }
+    });
+}
+
+impl RxPipe<bool> {
+    /// Send true when we receive true, then repeat it as set by `policy` until we receive false.
+    ///
+    /// False is sent when the condition clears, but only if a reminder was sent for it.
+    pub fn reminder(&self, policy: ReminderPolicy) -> RxPipe<bool> {
+        let output = self.new_output();
+        register_node("reminder", &[self.id()], &[output.id()]);
+        reminder(self.subscribe(), None, output.get_tx(), policy, Utc::now);
+        output.to_rx_pipe()
+    }
+
+    /// Like [Self::reminder], but a value from `snooze` delays the next reminder by that long.
+    pub fn reminder_with_snooze(
+        &self,
+        policy: ReminderPolicy,
+        snooze: &RxPipe<Duration>,
+    ) -> RxPipe<bool> {
+        let output = self.new_output();
+        register_node("reminder", &[self.id(), snooze.id()], &[output.id()]);
+        reminder(
+            self.subscribe(),
+            Some(snooze.subscribe()),
+            output.get_tx(),
+            policy,
+            Utc::now,
+        );
+        output.to_rx_pipe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet = QuietHours {
+            start: NaiveTime::from_hms(22, 0, 0),
+            end: NaiveTime::from_hms(7, 0, 0),
+            timezone: chrono_tz::Australia::Melbourne,
+        };
+        // 11pm and 9am in Melbourne, which is UTC+11 in January.
+        let night = Utc.ymd(2023, 1, 10).and_hms(12, 0, 0);
+        let day = Utc.ymd(2023, 1, 10).and_hms(22, 0, 0);
+        assert_eq!(quiet.remaining(night), Some(Duration::from_secs(8 * 3600)));
+        assert_eq!(quiet.remaining(day), None);
+
+        // Melbourne goes from UTC+11 to UTC+10 at 3am on 2 April 2023, so that night is an
+        // hour longer, and 1 October 2023 is an hour shorter.
+        let autumn = Utc.ymd(2023, 4, 1).and_hms(12, 0, 0);
+        let spring = Utc.ymd(2023, 9, 30).and_hms(13, 0, 0);
+        assert_eq!(quiet.remaining(autumn), Some(Duration::from_secs(9 * 3600)));
+        assert_eq!(quiet.remaining(spring), Some(Duration::from_secs(7 * 3600)));
+    }
+
+    #[test]
+    fn test_next_interval() {
+        let policy = ReminderPolicy {
+            intervals: vec![ms(1), ms(5)],
+            max_repeats: Some(3),
+            quiet_hours: None,
+        };
+        assert_eq!(policy.next_interval(1), Some(ms(1)));
+        assert_eq!(policy.next_interval(2), Some(ms(5)));
+        assert_eq!(policy.next_interval(3), Some(ms(5)));
+        assert_eq!(policy.next_interval(4), None);
+        assert_eq!(ReminderPolicy::every(ms(2)).next_interval(100), Some(ms(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reminder() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (snooze_tx, snooze_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let snooze = Injector::new(&clock, snooze_tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        let policy = ReminderPolicy {
+            intervals: vec![ms(100), ms(200)],
+            max_repeats: Some(3),
+            quiet_hours: None,
+        };
+        reminder(in_rx, Some(snooze_rx), out_tx, policy, Utc::now);
+
+        injector.script([(ms(0), true), (ms(50), true), (ms(1000), false)]);
+        snooze.script([(ms(150), ms(300))]);
+
+        recorder
+            .assert_recorded_by(
+                ms(1200),
+                &[
+                    (ms(0), true),
+                    (ms(100), true),
+                    (ms(450), true),
+                    (ms(650), true),
+                    (ms(1000), false),
+                ],
+            )
+            .await;
+    }
+
+    /// Get a wall clock that starts at `start` and follows tokio's paused time.
+    fn wall_clock(start: DateTime<Utc>) -> impl Fn() -> DateTime<Utc> + Send + 'static {
+        let base = Instant::now();
+        move || start + chrono::Duration::from_std(Instant::now() - base).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reminder_quiet_hours() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let (clear_tx, clear_in_rx) = channel(10);
+        let (clear_out_tx, clear_rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let clear_injector = Injector::new(&clock, clear_tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        let clear_recorder = PipeRecorder::new(&clock, clear_rx);
+
+        // Quiet hours start 150ms in and last a second.
+        let start = Utc.ymd(2023, 1, 10).and_hms_milli(21, 59, 59, 850);
+        let policy = ReminderPolicy {
+            intervals: vec![ms(200)],
+            max_repeats: Some(1),
+            quiet_hours: Some(QuietHours {
+                start: NaiveTime::from_hms(22, 0, 0),
+                end: NaiveTime::from_hms(22, 0, 1),
+                timezone: chrono_tz::UTC,
+            }),
+        };
+        reminder(in_rx, None, out_tx, policy.clone(), wall_clock(start));
+        reminder(clear_in_rx, None, clear_out_tx, policy, wall_clock(start));
+
+        // The reminder due at 200ms is sent when quiet hours end.
+        injector.script([(ms(0), true), (ms(1500), false)]);
+        // The condition clears in quiet hours, so the false is sent when they end.
+        clear_injector.script([(ms(0), true), (ms(500), false)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(2000),
+                &[(ms(0), true), (ms(1150), true), (ms(1500), false)],
+            )
+            .await;
+        clear_recorder
+            .assert_recorded_by(ms(2000), &[(ms(0), true), (ms(1150), false)])
+            .await;
+    }
+}
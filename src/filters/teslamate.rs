@@ -1,79 +1,12 @@
 //! Filter functions specific to teslamate.
-use tokio::{select, sync::broadcast};
-
-use crate::{recv, send_or_log, spawn, Pipe, RxPipe};
+use super::combine::combine_latest4;
+use super::logic::Unknown;
+use crate::RxPipe;
 
 fn _requires_plugin(
-    mut battery_level: broadcast::Receiver<usize>,
-    mut plugged_in: broadcast::Receiver<bool>,
-    mut geofence: broadcast::Receiver<String>,
-    mut reminder: broadcast::Receiver<bool>,
-    output: broadcast::Sender<bool>,
-) {
-    spawn(async move {
-        let mut the_battery_level: Option<usize> = None;
-        let mut the_plugged_in: Option<bool> = None;
-        let mut the_geofence: Option<String> = None;
-        let mut the_reminder: Option<bool> = None;
-
-        loop {
-            select! {
-                Ok(battery_level) = recv(&mut battery_level) => { the_battery_level = Some(battery_level)},
-                Ok(plugged_in) = recv(&mut plugged_in) => { the_plugged_in = Some(plugged_in)},
-                Ok(geofence) = recv(&mut geofence) => { the_geofence = Some(geofence)},
-                Ok(reminder) = recv(&mut reminder) => { the_reminder = Some(reminder)},
-                else => { break; }
-            }
-
-            match (
-                the_battery_level,
-                the_plugged_in,
-                the_geofence.as_deref(),
-                the_reminder,
-            ) {
-                (None, _, _, _) => {}
-                (_, None, _, _) => {}
-                (_, _, None, _) => {}
-                (_, _, _, None) => {}
-                (Some(level), Some(false), Some("Home"), Some(true)) if level < 75 => {
-                    send_or_log(&output, true);
-                }
-                (_, _, _, _) => {
-                    send_or_log(&output, false);
-                }
-            };
-        }
-    });
-}
-
-fn _is_insecure(
-    mut is_user_present: broadcast::Receiver<bool>,
-    mut locked: broadcast::Receiver<bool>,
-    output: broadcast::Sender<bool>,
-) {
-    spawn(async move {
-        let mut the_is_user_present: Option<bool> = None;
-        let mut the_locked: Option<bool> = None;
-
-        loop {
-            select! {
-                Ok(is_user_present) = recv(&mut is_user_present) => { the_is_user_present = Some(is_user_present)},
-                Ok(locked) = recv(&mut locked) => { the_locked = Some(locked)},
-                else => { break; }
-            }
-
-            match (the_is_user_present, the_locked) {
-                (None, _) => {}
-                (_, None) => {}
-                (Some(false), Some(false)) => {
-                    send_or_log(&output, true);
-                }
-                (_, _) => {
-                    send_or_log(&output, false);
-                }
-            };
-        }
-    });
+    (battery_level, plugged_in, geofence, reminder): (usize, bool, String, bool),
+) -> bool {
+    battery_level < 75 && !plugged_in && geofence == "Home" && reminder
 }
 
 /// Try to determine if the car requires a plugin.
@@ -83,24 +16,48 @@ pub fn requires_plugin(
     geofence: RxPipe<String>,
     reminder: RxPipe<bool>,
 ) -> RxPipe<bool> {
-    let output = Pipe::new();
-    _requires_plugin(
-        battery_level.subscribe(),
-        plugged_in.subscribe(),
-        geofence.subscribe(),
-        reminder.subscribe(),
-        output.get_tx(),
-    );
-    output.to_rx_pipe()
+    combine_latest4(battery_level, plugged_in, geofence, reminder)
+        .map(_requires_plugin)
+        .named("requires_plugin")
 }
 
 /// Try to determine if car is insecure.
 pub fn is_insecure(is_user_present: RxPipe<bool>, locked: RxPipe<bool>) -> RxPipe<bool> {
-    let output = Pipe::new();
-    _is_insecure(
-        is_user_present.subscribe(),
-        locked.subscribe(),
-        output.get_tx(),
-    );
-    output.to_rx_pipe()
+    is_user_present
+        .not()
+        .and(&locked.not(), Unknown::Withhold)
+        .named("is_insecure")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pipe;
+
+    #[test]
+    fn test_requires_plugin() {
+        let home = || "Home".to_string();
+        assert!(_requires_plugin((50, false, home(), true)));
+        assert!(!_requires_plugin((75, false, home(), true)));
+        assert!(!_requires_plugin((50, true, home(), true)));
+        assert!(!_requires_plugin((50, false, "Work".to_string(), true)));
+        assert!(!_requires_plugin((50, false, home(), false)));
+    }
+
+    #[tokio::test]
+    async fn test_is_insecure() {
+        let is_user_present = Pipe::new();
+        let locked = Pipe::new();
+        let mut out_rx = is_insecure(is_user_present.to_rx_pipe(), locked.to_rx_pipe()).subscribe();
+
+        is_user_present.get_tx().send(false).await.unwrap();
+        locked.get_tx().send(false).await.unwrap();
+        assert!(out_rx.recv().await.unwrap());
+        is_user_present.get_tx().send(true).await.unwrap();
+        assert!(!out_rx.recv().await.unwrap());
+        is_user_present.get_tx().send(false).await.unwrap();
+        assert!(out_rx.recv().await.unwrap());
+        locked.get_tx().send(true).await.unwrap();
+        assert!(!out_rx.recv().await.unwrap());
+    }
 }
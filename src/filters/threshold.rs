@@ -0,0 +1,240 @@
+//! Convert numbers to booleans with hysteresis, so noise near a threshold does not flap.
+use std::time::Duration;
+
+use tokio::select;
+use tokio::time::Instant;
+
+use super::aggregate::Number;
+use super::timers::maybe_sleep_until;
+use crate::registry::register_node;
+use crate::{recv, send_or_log, spawn, Receiver, RxPipe, Sender};
+
+/// When [RxPipe::hysteresis_with_dwell] turns on and off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold<T> {
+    /// On at or below `on_below`, off above `off_above`.
+    Falling {
+        /// Turn on at or below this value.
+        on_below: T,
+        /// Turn off above this value.
+        off_above: T,
+    },
+    /// On at or above `on_above`, off below `off_below`.
+    Rising {
+        /// Turn on at or above this value.
+        on_above: T,
+        /// Turn off below this value.
+        off_below: T,
+    },
+}
+
+impl<T: Number> Threshold<T> {
+    fn check(&self) {
+        let valid = match *self {
+            Threshold::Falling {
+                on_below,
+                off_above,
+            } => on_below <= off_above,
+            Threshold::Rising {
+                on_above,
+                off_below,
+            } => off_below <= on_above,
+        };
+        assert!(valid, "the thresholds of {self:?} overlap");
+    }
+
+    /// Get the state after receiving `value`, values between the thresholds keep `state`.
+    fn next(&self, state: bool, value: T) -> bool {
+        match *self {
+            Threshold::Falling {
+                on_below,
+                off_above,
+            } => match value {
+                v if v <= on_below => true,
+                v if v > off_above => false,
+                _ => state,
+            },
+            Threshold::Rising {
+                on_above,
+                off_below,
+            } => match value {
+                v if v >= on_above => true,
+                v if v < off_below => false,
+                _ => state,
+            },
+        }
+    }
+}
+
+/// How long values must stay past a threshold before switching.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Dwell {
+    /// Time before switching on.
+    pub on: Duration,
+    /// Time before switching off.
+    pub off: Duration,
+}
+
+impl Dwell {
+    fn get(&self, state: bool) -> Duration {
+        if state {
+            self.on
+        } else {
+            self.off
+        }
+    }
+}
+
+fn hysteresis<T: Number>(
+    mut input: Receiver<T>,
+    output: Sender<bool>,
+    threshold: Threshold<T>,
+    dwell: Dwell,
+) {
+    spawn(async move {
+        let mut state: Option<bool> = None;
+        let mut switch_at: Option<Instant> = None;
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    let current = state.unwrap_or(false);
+                    let next = threshold.next(current, v);
+                    let duration = dwell.get(next);
+                    if state.is_none() || (next != current && duration.is_zero()) {
+                        state = Some(next);
+                        switch_at = None;
+                    } else if next == current {
+                        switch_at = None;
+                    } else if switch_at.is_none() {
+                        switch_at = Some(Instant::now() + duration);
+                    }
+                    send_or_log(&output, state.unwrap_or(next)).await;
+                },
+                Some(()) = maybe_sleep_until(switch_at) => {
+                    switch_at = None;
+                    let next = !state.unwrap_or(false);
+                    state = Some(next);
+                    send_or_log(&output, next).await;
+                },
+                else => { break; }
+            }
+        }
+    });
+}
+
+impl<T: Number> RxPipe<T> {
+    /// Send true at or below `on_below`, false above `off_above`, and the last state in between.
+    ///
+    /// A value is sent for every value received, the first value in between sends false.
+    pub fn hysteresis(&self, on_below: T, off_above: T) -> RxPipe<bool> {
+        let threshold = Threshold::Falling {
+            on_below,
+            off_above,
+        };
+        self.hysteresis_with_dwell(threshold, Dwell::default())
+    }
+
+    /// Send true at or above `on_above`, false below `off_below`, and the last state in between.
+    ///
+    /// A value is sent for every value received, the first value in between sends false.
+    pub fn hysteresis_rising(&self, on_above: T, off_below: T) -> RxPipe<bool> {
+        let threshold = Threshold::Rising {
+            on_above,
+            off_below,
+        };
+        self.hysteresis_with_dwell(threshold, Dwell::default())
+    }
+
+    /// Like [Self::hysteresis], but values must stay past the threshold for `dwell` first.
+    ///
+    /// The state switches once the dwell time is up, even if no more values arrive. The first
+    /// value sets the state straight away.
+    pub fn hysteresis_with_dwell(&self, threshold: Threshold<T>, dwell: Dwell) -> RxPipe<bool> {
+        threshold.check();
+        let output = self.new_output();
+        register_node("hysteresis", &[self.id()], &[output.id()]);
+        hysteresis(self.subscribe(), output.get_tx(), threshold, dwell);
+        output.to_rx_pipe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+
+    #[test]
+    fn test_threshold() {
+        let falling = Threshold::Falling {
+            on_below: 2.0,
+            off_above: 3.0,
+        };
+        assert!(falling.next(false, 2.0));
+        assert!(!falling.next(false, 2.5));
+        assert!(falling.next(true, 2.5));
+        assert!(falling.next(true, 3.0));
+        assert!(!falling.next(true, 3.1));
+
+        let rising = Threshold::Rising {
+            on_above: 30,
+            off_below: 25,
+        };
+        assert!(rising.next(false, 30));
+        assert!(rising.next(true, 25));
+        assert!(!rising.next(true, 24));
+        assert!(!rising.next(false, 29));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overlapping_thresholds() {
+        Threshold::Falling {
+            on_below: 3,
+            off_above: 2,
+        }
+        .check();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hysteresis_with_dwell() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        let threshold = Threshold::Falling {
+            on_below: 2.0,
+            off_above: 3.0,
+        };
+        let dwell = Dwell {
+            on: Duration::ZERO,
+            off: ms(100),
+        };
+        hysteresis(in_rx, out_tx, threshold, dwell);
+
+        injector.script([
+            (ms(0), 2.5),
+            (ms(10), 1.0),
+            (ms(20), 4.0),
+            (ms(50), 2.5),
+            (ms(200), 4.0),
+        ]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[
+                    (ms(0), false),
+                    (ms(10), true),
+                    (ms(20), true),
+                    (ms(50), true),
+                    (ms(200), true),
+                    (ms(300), false),
+                ],
+            )
+            .await;
+    }
+}
@@ -1,12 +1,22 @@
 //! Filter functions for timers
-use std::time::Duration;
+//!
+//! When the input pipe is closed, values waiting in [RxPipe::delay], [RxPipe::delay_when],
+//! [RxPipe::debounce] and [RxPipe::throttle] are sent straight away, so they are not lost
+//! on shutdown. Other pending timers are discarded.
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use tokio::time::{self, sleep_until, Interval};
-use tokio::{select, sync::broadcast, time::Instant};
+use tokio::sync::Mutex;
+use tokio::time::{self, sleep_until};
+use tokio::{select, time::Instant};
 
-use crate::{recv, send_or_log, spawn, Pipe, RxPipe};
+use crate::persist;
+use crate::registry::{register_named_node, register_node};
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::{recv, send_or_log, spawn, Receiver, RxPipe, Sender};
 
-async fn maybe_sleep_until(instant: Option<Instant>) -> Option<()> {
+pub(super) async fn maybe_sleep_until(instant: Option<Instant>) -> Option<()> {
     if let Some(instant) = instant {
         sleep_until(instant).await;
         Some(())
@@ -15,27 +25,54 @@ async fn maybe_sleep_until(instant: Option<Instant>) -> Option<()> {
     }
 }
 
+/// Convert a deadline to wall clock time, so it can be persisted.
+fn to_system_time(instant: Instant) -> SystemTime {
+    SystemTime::now() + instant.saturating_duration_since(Instant::now())
+}
+
+/// Convert a persisted deadline back to an [Instant], deadlines in the past are due now.
+fn from_system_time(time: SystemTime) -> Instant {
+    Instant::now() + time.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
 fn delay_true(
-    mut input: broadcast::Receiver<bool>,
-    output: broadcast::Sender<bool>,
+    mut input: Receiver<bool>,
+    output: Sender<bool>,
     duration: Duration,
+    id: Option<String>,
 ) {
     spawn(async move {
-        let mut delay_until: Option<Instant> = None;
+        let mut delay_until: Option<Instant> = id
+            .as_deref()
+            .and_then(persist::load::<SystemTime>)
+            .map(from_system_time);
+
+        let save = |delay_until: Option<Instant>| {
+            if let Some(id) = &id {
+                match delay_until {
+                    Some(instant) => persist::save(id, &to_system_time(instant)),
+                    None => persist::remove(id),
+                }
+            }
+        };
 
         loop {
             select! {
-                Ok(v) = recv(&mut input) => {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
                     if v && delay_until.is_none() {
                         delay_until = Some(Instant::now() + duration);
+                        save(delay_until);
                     } else if !v {
                         delay_until = None;
-                        send_or_log(&output, v);
+                        save(delay_until);
+                        send_or_log(&output, v).await;
                     }
                 },
                 Some(()) = maybe_sleep_until(delay_until) => {
                     delay_until = None;
-                    send_or_log(&output, true)
+                    save(delay_until);
+                    send_or_log(&output, true).await
                 },
                 else => { break; }
             }
@@ -44,8 +81,8 @@ fn delay_true(
 }
 
 fn startup_delay<T: Send + Clone + 'static>(
-    mut input: broadcast::Receiver<T>,
-    output: broadcast::Sender<T>,
+    mut input: Receiver<T>,
+    output: Sender<T>,
     duration: Duration,
     value: T,
 ) {
@@ -55,79 +92,331 @@ fn startup_delay<T: Send + Clone + 'static>(
 
         loop {
             select! {
-                Ok(v) = recv(&mut input) => {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
                     delay_until = None;
-                    send_or_log(&output, v);
+                    send_or_log(&output, v).await;
                 },
                 Some(()) = maybe_sleep_until(delay_until) => {
                     delay_until = None;
                     if let Some(value) = value.take() {
-                        send_or_log(&output, value);
+                        send_or_log(&output, value).await;
+                    }
+                },
+                else => { break; }
+            }
+        }
+    });
+}
+
+fn delay<T: Send + Clone + 'static>(mut input: Receiver<T>, output: Sender<T>, duration: Duration) {
+    spawn(async move {
+        let mut pending: VecDeque<(Instant, T)> = VecDeque::new();
+
+        loop {
+            let next = pending.front().map(|(instant, _)| *instant);
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    pending.push_back((Instant::now() + duration, v));
+                },
+                Some(()) = maybe_sleep_until(next) => {
+                    if let Some((_, v)) = pending.pop_front() {
+                        send_or_log(&output, v).await;
                     }
                 },
                 else => { break; }
             }
         }
+
+        for (_, v) in pending {
+            send_or_log(&output, v).await;
+        }
+    });
+}
+
+fn delay_when<T: Send + Clone + 'static>(
+    input: Receiver<T>,
+    output: Sender<T>,
+    duration: Duration,
+    predicate: impl Fn(&T) -> bool + Send + Sync + 'static,
+) {
+    let input = Arc::new(Mutex::new(input));
+    let predicate = Arc::new(predicate);
+    supervise("delay_when", RestartPolicy::default(), move || {
+        let input = input.clone();
+        let output = output.clone();
+        let predicate = predicate.clone();
+        async move {
+            let mut input = input.lock().await;
+            let mut delay_until: Option<Instant> = None;
+            let mut value: Option<T> = None;
+
+            loop {
+                select! {
+                    v = recv(&mut input) => {
+                        let Ok(v) = v else { break; };
+                        if !predicate(&v) {
+                            delay_until = None;
+                            value = None;
+                            send_or_log(&output, v).await;
+                        } else {
+                            if delay_until.is_none() {
+                                delay_until = Some(Instant::now() + duration);
+                            }
+                            value = Some(v);
+                        }
+                    },
+                    Some(()) = maybe_sleep_until(delay_until) => {
+                        delay_until = None;
+                        if let Some(value) = value.take() {
+                            send_or_log(&output, value).await;
+                        }
+                    },
+                    else => { break; }
+                }
+            }
+
+            if let Some(value) = value {
+                send_or_log(&output, value).await;
+            }
+        }
+    });
+}
+
+fn expire<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<Option<T>>,
+    duration: Duration,
+) {
+    spawn(async move {
+        let mut expire_at: Option<Instant> = None;
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    expire_at = Some(Instant::now() + duration);
+                    send_or_log(&output, Some(v)).await;
+                },
+                Some(()) = maybe_sleep_until(expire_at) => {
+                    expire_at = None;
+                    send_or_log(&output, None).await;
+                },
+                else => { break; }
+            }
+        }
     });
 }
 
-fn delay_cancel(
-    mut input: broadcast::Receiver<bool>,
-    output: broadcast::Sender<bool>,
+fn debounce<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<T>,
     duration: Duration,
 ) {
     spawn(async move {
         let mut delay_until: Option<Instant> = None;
+        let mut value: Option<T> = None;
 
         loop {
             select! {
-                Ok(v) = recv(&mut input) => {
-                    if v {
-                        delay_until = Some(Instant::now() + duration);
-                    } else {
-                        delay_until = None;
-                    }
-                    send_or_log(&output, v);
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    delay_until = Some(Instant::now() + duration);
+                    value = Some(v);
                 },
                 Some(()) = maybe_sleep_until(delay_until) => {
                     delay_until = None;
-                    send_or_log(&output, false)
+                    if let Some(value) = value.take() {
+                        send_or_log(&output, value).await;
+                    }
                 },
                 else => { break; }
             }
         }
+
+        if let Some(value) = value {
+            send_or_log(&output, value).await;
+        }
     });
 }
 
-async fn maybe_tick(interval: &mut Option<Interval>) -> Option<()> {
-    if let Some(interval) = interval {
-        interval.tick().await;
-        Some(())
-    } else {
-        None
+/// Which values [RxPipe::throttle] sends.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ThrottleEdge {
+    /// Send the first value straight away, and drop the rest until the time is up.
+    Leading,
+    /// Wait until the time is up, then send the last value received.
+    Trailing,
+    /// Send the first value straight away, and the last value once the time is up.
+    Both,
+}
+
+impl ThrottleEdge {
+    fn leading(self) -> bool {
+        matches!(self, ThrottleEdge::Leading | ThrottleEdge::Both)
     }
+
+    fn trailing(self) -> bool {
+        matches!(self, ThrottleEdge::Trailing | ThrottleEdge::Both)
+    }
+}
+
+fn throttle<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<T>,
+    duration: Duration,
+    edge: ThrottleEdge,
+) {
+    spawn(async move {
+        let mut window_until: Option<Instant> = None;
+        let mut value: Option<T> = None;
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    if window_until.is_none() {
+                        window_until = Some(Instant::now() + duration);
+                        if edge.leading() {
+                            send_or_log(&output, v).await;
+                        } else {
+                            value = Some(v);
+                        }
+                    } else if edge.trailing() {
+                        value = Some(v);
+                    }
+                },
+                Some(()) = maybe_sleep_until(window_until) => {
+                    window_until = None;
+                    if let Some(value) = value.take() {
+                        // Sending the trailing value starts a new window.
+                        window_until = Some(Instant::now() + duration);
+                        send_or_log(&output, value).await;
+                    }
+                },
+                else => { break; }
+            }
+        }
+
+        if let Some(value) = value {
+            send_or_log(&output, value).await;
+        }
+    });
+}
+
+fn sample<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<T>,
+    duration: Duration,
+) {
+    spawn(async move {
+        let mut interval = time::interval_at(Instant::now() + duration, duration);
+        let mut value: Option<T> = None;
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    value = Some(v);
+                },
+                _ = interval.tick() => {
+                    if let Some(value) = value.take() {
+                        send_or_log(&output, value).await;
+                    }
+                },
+            }
+        }
+    });
+}
+
+/// A value from [RxPipe::watchdog].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Freshness<T> {
+    /// A value was received in time.
+    Fresh(T),
+    /// Nothing was received in time.
+    Stale,
+}
+
+impl<T> Freshness<T> {
+    /// Is this the stale signal?
+    pub fn is_stale(&self) -> bool {
+        matches!(self, Freshness::Stale)
+    }
+
+    /// Get the value, if it is fresh.
+    pub fn fresh(self) -> Option<T> {
+        match self {
+            Freshness::Fresh(v) => Some(v),
+            Freshness::Stale => None,
+        }
+    }
+}
+
+fn watchdog<T: Send + Clone + 'static>(
+    mut input: Receiver<T>,
+    output: Sender<Freshness<T>>,
+    duration: Duration,
+) {
+    spawn(async move {
+        let mut stale_at: Option<Instant> = Some(Instant::now() + duration);
+
+        loop {
+            select! {
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    stale_at = Some(Instant::now() + duration);
+                    send_or_log(&output, Freshness::Fresh(v)).await;
+                },
+                Some(()) = maybe_sleep_until(stale_at) => {
+                    stale_at = None;
+                    send_or_log(&output, Freshness::Stale).await;
+                },
+                else => { break; }
+            }
+        }
+    });
 }
 
 fn timer_true(
-    mut input: broadcast::Receiver<bool>,
-    output: broadcast::Sender<bool>,
+    mut input: Receiver<bool>,
+    output: Sender<bool>,
     duration: Duration,
+    id: Option<String>,
 ) {
     spawn(async move {
-        let mut interval: Option<Interval> = None;
+        let mut next_tick: Option<Instant> = id
+            .as_deref()
+            .and_then(persist::load::<SystemTime>)
+            .map(from_system_time);
+
+        let save = |next_tick: Option<Instant>| {
+            if let Some(id) = &id {
+                match next_tick {
+                    Some(instant) => persist::save(id, &to_system_time(instant)),
+                    None => persist::remove(id),
+                }
+            }
+        };
 
         loop {
             select! {
-                Ok(v) = recv(&mut input) => {
-                    if v && interval.is_none() {
-                        interval = Some(time::interval(duration));
+                v = recv(&mut input) => {
+                    let Ok(v) = v else { break; };
+                    if v && next_tick.is_none() {
+                        next_tick = Some(Instant::now());
+                        save(next_tick);
                     } else if !v {
-                        interval = None;
-                        send_or_log(&output, v);
+                        next_tick = None;
+                        save(next_tick);
+                        send_or_log(&output, v).await;
                     }
                 },
-                Some(()) = maybe_tick(&mut interval) => {
-                    send_or_log(&output, true)
+                Some(()) = maybe_sleep_until(next_tick) => {
+                    next_tick = Some(Instant::now() + duration);
+                    save(next_tick);
+                    send_or_log(&output, true).await
                 },
                 else => { break; }
             }
@@ -141,10 +430,91 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
     /// On startup it isn't always clear if we are going to received an initial value or not.
     /// If we don't receive an initial value with in time then we will send the provided value on startup.
     pub fn startup_delay(&self, duration: Duration, value: T) -> RxPipe<T> {
-        let output = Pipe::new();
+        let output = self.new_output();
+        register_node("startup_delay", &[self.id()], &[output.id()]);
         startup_delay(self.subscribe(), output.get_tx(), duration, value);
         output.to_rx_pipe()
     }
+
+    /// Send every value `duration` after it was received.
+    pub fn delay(&self, duration: Duration) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("delay", &[self.id()], &[output.id()]);
+        delay(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Delay values matching `predicate`, and pass on other values straight away.
+    ///
+    /// A matching value is sent `duration` after the first matching value, unless a value
+    /// that does not match arrives first. Later matching values replace the value to send,
+    /// but do not restart the timer.
+    pub fn delay_when(
+        &self,
+        predicate: impl Fn(&T) -> bool + Send + Sync + 'static,
+        duration: Duration,
+    ) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("delay_when", &[self.id()], &[output.id()]);
+        delay_when(self.subscribe(), output.get_tx(), duration, predicate);
+        output.to_rx_pipe()
+    }
+
+    /// Send every value, then send `None` if no newer value arrives within `duration`.
+    ///
+    /// Values are only sent once, so a `None` marks the last value as expired.
+    pub fn expire(&self, duration: Duration) -> RxPipe<Option<T>> {
+        let output = self.new_output();
+        register_node("expire", &[self.id()], &[output.id()]);
+        expire(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Wait until no value has been received for `duration`, then send the last value.
+    pub fn debounce(&self, duration: Duration) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("debounce", &[self.id()], &[output.id()]);
+        debounce(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Send at most one value every `duration`, `edge` decides which one.
+    pub fn throttle(&self, duration: Duration, edge: ThrottleEdge) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("throttle", &[self.id()], &[output.id()]);
+        throttle(self.subscribe(), output.get_tx(), duration, edge);
+        output.to_rx_pipe()
+    }
+
+    /// Send the last value received every `duration`, if there was a new one.
+    pub fn sample(&self, duration: Duration) -> RxPipe<T> {
+        let output = self.new_output();
+        register_node("sample", &[self.id()], &[output.id()]);
+        sample(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Pass on every value as fresh, and send stale once if nothing arrives for `duration`.
+    ///
+    /// The timer starts when the pipe is created, and again after every value.
+    pub fn watchdog(&self, duration: Duration) -> RxPipe<Freshness<T>> {
+        let output = self.new_output();
+        register_node("watchdog", &[self.id()], &[output.id()]);
+        watchdog(self.subscribe(), output.get_tx(), duration);
+        output.to_rx_pipe()
+    }
+
+    /// Send true when nothing has arrived for `duration`, and false when values arrive again.
+    ///
+    /// Only changes are sent. The pipe starts out fresh, so nothing is sent if the first value
+    /// arrives within `duration`, otherwise true is sent and then false on the first value.
+    pub fn stale(&self, duration: Duration) -> RxPipe<bool> {
+        self.watchdog(duration)
+            .map(|v| v.is_stale())
+            .diff_with_initial_value(Some(false))
+            .changed()
+            .named("stale")
+    }
 }
 
 impl RxPipe<bool> {
@@ -152,81 +522,447 @@ impl RxPipe<bool> {
     ///
     /// If we receive a false value, immediately pass it on to the next pipe and cancel the true value.
     pub fn delay_true(&self, duration: Duration) -> RxPipe<bool> {
-        let output = Pipe::new();
-        delay_true(self.subscribe(), output.get_tx(), duration);
+        let output = self.new_output();
+        register_node("delay_true", &[self.id()], &[output.id()]);
+        delay_true(self.subscribe(), output.get_tx(), duration, None);
         output.to_rx_pipe()
     }
+
+    /// Like [Self::delay_true], but a pending true value survives a restart.
+    ///
+    /// The deadline is persisted under `id`, see [persist]. If the deadline passed while we
+    /// were down, true is sent on startup.
+    pub fn delay_true_persisted(&self, id: &str, duration: Duration) -> RxPipe<bool> {
+        let output = self.new_output();
+        register_named_node("delay_true", id, &[self.id()], &[output.id()]);
+        persist::claim(id);
+        delay_true(
+            self.subscribe(),
+            output.get_tx(),
+            duration,
+            Some(id.to_string()),
+        );
+        output.to_rx_pipe()
+    }
+
     /// If we receive a true value then wait before automatically sending false value.
     pub fn delay_cancel(&self, duration: Duration) -> RxPipe<bool> {
-        let output = Pipe::new();
-        delay_cancel(self.subscribe(), output.get_tx(), duration);
-        output.to_rx_pipe()
+        self.expire(duration)
+            .map_with_state(false, |last, v| match v {
+                Some(v) => {
+                    *last = v;
+                    Some(v)
+                }
+                // Only a true value expires to false.
+                None => std::mem::take(last).then_some(false),
+            })
+            .filter_map(|v| v)
+            .named("delay_cancel")
+    }
+
+    /// Like [Self::delay_true], but delays false values instead.
+    pub fn delay_false(&self, duration: Duration) -> RxPipe<bool> {
+        self.delay_when(|v| !*v, duration).named("delay_false")
     }
 
     /// If we receive true value, start timer until we receive false value.
     pub fn timer_true(&self, duration: Duration) -> RxPipe<bool> {
-        let output = Pipe::new();
-        timer_true(self.subscribe(), output.get_tx(), duration);
+        let output = self.new_output();
+        register_node("timer_true", &[self.id()], &[output.id()]);
+        timer_true(self.subscribe(), output.get_tx(), duration, None);
+        output.to_rx_pipe()
+    }
+
+    /// Like [Self::timer_true], but the timer keeps running across a restart.
+    ///
+    /// The next tick is persisted under `id`, see [persist]. If it passed while we were down,
+    /// true is sent on startup.
+    pub fn timer_true_persisted(&self, id: &str, duration: Duration) -> RxPipe<bool> {
+        let output = self.new_output();
+        register_named_node("timer_true", id, &[self.id()], &[output.id()]);
+        persist::claim(id);
+        timer_true(
+            self.subscribe(),
+            output.get_tx(),
+            duration,
+            Some(id.to_string()),
+        );
         output.to_rx_pipe()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::time::sleep;
-
     use super::*;
+    use crate::channel;
+    use crate::testing::{ms, Injector, PipeRecorder, TestClock};
+    use crate::Pipe;
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_delay_true() {
-        let duration = Duration::from_millis(100);
-        let wait_duration = Duration::from_millis(200);
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        delay_true(in_rx, out_tx, ms(100), None);
+
+        injector.script([
+            (ms(0), false),
+            (ms(50), true),
+            (ms(80), false),
+            (ms(200), true),
+        ]);
 
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
-        delay_true(in_rx, out_tx, duration);
+        recorder
+            .assert_recorded_by(ms(400), &[(ms(0), false), (ms(80), false), (ms(300), true)])
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_true_persisted() {
+        let id = "test_delay_true_persisted";
+        persist::save(id, &(SystemTime::now() - Duration::from_secs(1)));
 
-        tx.send(false).unwrap();
-        let v = rx.recv().await.unwrap();
-        assert!(!v);
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        delay_true(in_rx, out_tx, ms(100), Some(id.to_string()));
 
-        tx.send(true).unwrap();
-        tx.send(false).unwrap();
-        let v = rx.recv().await.unwrap();
-        assert!(!v);
+        injector.script([(ms(50), true)]);
 
-        tx.send(true).unwrap();
-        sleep(wait_duration).await;
-        let v = rx.try_recv().unwrap();
-        assert!(matches!(v, true));
+        recorder
+            .assert_recorded_by(ms(200), &[(ms(0), true), (ms(150), true)])
+            .await;
+        assert_eq!(persist::load::<SystemTime>(id), None);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_cancel() {
+        let clock = TestClock::new();
+        let input = Pipe::new();
+        let injector = Injector::new(&clock, input.get_tx());
+        let recorder =
+            PipeRecorder::new(&clock, input.to_rx_pipe().delay_cancel(ms(100)).subscribe());
+
+        injector.script([
+            (ms(0), true),
+            (ms(50), true),
+            (ms(200), false),
+            (ms(250), true),
+        ]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[
+                    (ms(0), true),
+                    (ms(50), true),
+                    (ms(150), false),
+                    (ms(200), false),
+                    (ms(250), true),
+                    (ms(350), false),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
     async fn test_timer_true() {
-        let duration = Duration::from_millis(100);
-        let wait_duration = Duration::from_millis(200);
-
-        let (tx, in_rx) = broadcast::channel(10);
-        let (out_tx, mut rx) = broadcast::channel(10);
-        timer_true(in_rx, out_tx, duration);
-
-        tx.send(false).unwrap();
-        let v = rx.recv().await.unwrap();
-        assert!(!v);
-
-        tx.send(true).unwrap();
-        tx.send(false).unwrap();
-        // FIXME: Possible race condition, one true value could get sent before timer gets cancelled.
-        let v = rx.recv().await.unwrap();
-        assert!(!v);
-
-        tx.send(true).unwrap();
-        sleep(wait_duration).await;
-        let v = rx.try_recv().unwrap();
-        assert!(matches!(v, true));
-
-        sleep(wait_duration).await;
-        let v = rx.try_recv().unwrap();
-        assert!(matches!(v, true));
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        timer_true(in_rx, out_tx, ms(100), None);
+
+        injector.script([
+            (ms(0), false),
+            (ms(50), true),
+            (ms(80), false),
+            (ms(200), true),
+        ]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[
+                    (ms(0), false),
+                    (ms(50), true),
+                    (ms(80), false),
+                    (ms(200), true),
+                    (ms(300), true),
+                    (ms(400), true),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_true_persisted() {
+        let id = "test_timer_true_persisted";
+        persist::save(id, &(SystemTime::now() - Duration::from_secs(1)));
+
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        timer_true(in_rx, out_tx, ms(100), Some(id.to_string()));
+
+        injector.script([(ms(50), true), (ms(250), false)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[
+                    (ms(0), true),
+                    (ms(100), true),
+                    (ms(200), true),
+                    (ms(250), false),
+                ],
+            )
+            .await;
+        assert_eq!(persist::load::<SystemTime>(id), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        delay(in_rx, out_tx, ms(100));
+
+        injector.script([(ms(0), 1), (ms(30), 2), (ms(200), 3)]);
+
+        recorder
+            .assert_recorded_by(ms(400), &[(ms(100), 1), (ms(130), 2), (ms(300), 3)])
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_when() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        delay_when(in_rx, out_tx, ms(100), |v: &i32| *v > 10);
+
+        injector.script([
+            (ms(0), 1),
+            (ms(10), 20),
+            (ms(50), 2),
+            (ms(200), 30),
+            (ms(250), 40),
+        ]);
+
+        recorder
+            .assert_recorded_by(ms(400), &[(ms(0), 1), (ms(50), 2), (ms(300), 40)])
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_when_restarts_after_panic() {
+        let (tx, in_rx) = channel(10);
+        let (out_tx, mut rx) = channel(10);
+        delay_when(in_rx, out_tx, ms(100), |v: &i32| {
+            assert!(*v != 0, "bad value");
+            *v > 10
+        });
+
+        tx.send(0).await.unwrap();
+        tx.send(1).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_false() {
+        let clock = TestClock::new();
+        let input = Pipe::new();
+        let injector = Injector::new(&clock, input.get_tx());
+        let recorder =
+            PipeRecorder::new(&clock, input.to_rx_pipe().delay_false(ms(100)).subscribe());
+
+        injector.script([
+            (ms(0), true),
+            (ms(50), false),
+            (ms(80), false),
+            (ms(200), false),
+            (ms(250), true),
+            (ms(300), false),
+        ]);
+
+        recorder
+            .assert_recorded_by(
+                ms(500),
+                &[
+                    (ms(0), true),
+                    (ms(150), false),
+                    (ms(250), true),
+                    (ms(400), false),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        expire(in_rx, out_tx, ms(100));
+
+        injector.script([(ms(0), 1), (ms(50), 2), (ms(300), 3)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(500),
+                &[
+                    (ms(0), Some(1)),
+                    (ms(50), Some(2)),
+                    (ms(150), None),
+                    (ms(300), Some(3)),
+                    (ms(400), None),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        debounce(in_rx, out_tx, ms(100));
+
+        injector.script([(ms(0), 1), (ms(50), 2), (ms(100), 3), (ms(300), 4)]);
+
+        recorder
+            .assert_recorded_by(ms(500), &[(ms(200), 3), (ms(400), 4)])
+            .await;
+    }
+
+    async fn assert_throttle(edge: ThrottleEdge, expected: &[(Duration, i32)]) {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        throttle(in_rx, out_tx, ms(100), edge);
+
+        injector.script([
+            (ms(0), 1),
+            (ms(50), 2),
+            (ms(80), 3),
+            (ms(150), 4),
+            (ms(260), 5),
+        ]);
+
+        recorder.assert_recorded_by(ms(500), expected).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_leading() {
+        let expected = [(ms(0), 1), (ms(150), 4), (ms(260), 5)];
+        assert_throttle(ThrottleEdge::Leading, &expected).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_trailing() {
+        let expected = [(ms(100), 3), (ms(200), 4), (ms(300), 5)];
+        assert_throttle(ThrottleEdge::Trailing, &expected).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_both() {
+        let expected = [(ms(0), 1), (ms(100), 3), (ms(200), 4), (ms(300), 5)];
+        assert_throttle(ThrottleEdge::Both, &expected).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sample() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        sample(in_rx, out_tx, ms(100));
+
+        injector.script([
+            (ms(0), 1),
+            (ms(50), 2),
+            (ms(150), 3),
+            (ms(160), 4),
+            (ms(350), 5),
+        ]);
+
+        recorder
+            .assert_recorded_by(ms(500), &[(ms(100), 2), (ms(200), 4), (ms(400), 5)])
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog() {
+        let clock = TestClock::new();
+        let (tx, in_rx) = channel(10);
+        let (out_tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+        watchdog(in_rx, out_tx, ms(100));
+
+        injector.script([(ms(50), 1), (ms(120), 2), (ms(400), 3)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(600),
+                &[
+                    (ms(50), Freshness::Fresh(1)),
+                    (ms(120), Freshness::Fresh(2)),
+                    (ms(220), Freshness::Stale),
+                    (ms(400), Freshness::Fresh(3)),
+                    (ms(500), Freshness::Stale),
+                ],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale() {
+        let clock = TestClock::new();
+        let input = Pipe::new();
+        let injector = Injector::new(&clock, input.get_tx());
+        let recorder = PipeRecorder::new(&clock, input.to_rx_pipe().stale(ms(100)).subscribe());
+
+        injector.script([(ms(150), 1), (ms(200), 2), (ms(250), 3)]);
+
+        recorder
+            .assert_recorded_by(
+                ms(400),
+                &[(ms(100), true), (ms(150), false), (ms(350), true)],
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_fresh_at_start() {
+        let clock = TestClock::new();
+        let input = Pipe::new();
+        let injector = Injector::new(&clock, input.get_tx());
+        let recorder = PipeRecorder::new(&clock, input.to_rx_pipe().stale(ms(100)).subscribe());
+
+        injector.script([(ms(50), 1), (ms(100), 2)]);
+
+        recorder
+            .assert_recorded_by(ms(300), &[(ms(200), true)])
+            .await;
     }
 }
@@ -2,21 +2,375 @@
 //! Provide functionality to process asynchronous streams of data for IOT devices.
 
 pub mod filters;
+pub mod metrics;
+pub mod persist;
+pub mod registry;
+pub mod reload;
+pub mod runtime;
 pub mod sinks;
 pub mod sources;
+pub mod state;
+pub mod stream;
+pub mod supervisor;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trace;
 
 use anyhow::anyhow;
 use anyhow::Result;
 use log::*;
+use metrics::PipeMetrics;
+use registry::PipeId;
+use std::fmt::{self, Display};
 use std::future::Future;
-use tokio::sync::broadcast::error::RecvError;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::broadcast::error::{RecvError, SendError, TryRecvError};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
 use tokio::{sync::broadcast, task::JoinHandle};
+use trace::Envelope;
 
 const PIPE_SIZE: usize = 10;
 
-/// Send a value to a broadcast channel, but use error value that does not contain the value.
-pub fn send<T>(tx: &broadcast::Sender<T>, data: T) -> Result<()> {
-    let rc = tx.send(data);
+/// What a pipe does with new messages when a receiver cannot keep up.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest messages, so the receiver keeps the latest values.
+    ///
+    /// The receiver is told how many messages it missed.
+    #[default]
+    DropOldest,
+    /// Drop new messages until the receiver has caught up.
+    ///
+    /// Dropped messages are counted in the [metrics] of the pipe.
+    DropNewest,
+    /// Wait until every receiver has room for the message, so nothing is lost.
+    ///
+    /// A slow receiver slows down the sender, and with it every other receiver.
+    Backpressure,
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::Backpressure => "backpressure",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Fan-out to a bounded queue per receiver, used by every policy except [OverflowPolicy::DropOldest].
+struct Queue<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    receivers: Mutex<Vec<mpsc::Sender<T>>>,
+}
+
+impl<T> Queue<T> {
+    fn subscribe(&self) -> mpsc::Receiver<T> {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        self.receivers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// Subscribe to a queue, if the queue is gone the receiver is already closed.
+fn subscribe_queue<T>(queue: &Weak<Queue<T>>) -> mpsc::Receiver<T> {
+    match queue.upgrade() {
+        Some(queue) => queue.subscribe(),
+        None => mpsc::channel(1).1,
+    }
+}
+
+enum SenderInner<T> {
+    Broadcast(broadcast::Sender<Envelope<T>>),
+    Queue(Arc<Queue<Envelope<T>>>),
+}
+
+/// The sending half of a pipe.
+///
+/// This wraps a broadcast sender, or a queue per receiver, depending on the
+/// [OverflowPolicy]. It records [metrics] for every message sent, and attaches the
+/// [trace] of the last message received by the sending task.
+pub struct Sender<T> {
+    tx: SenderInner<T>,
+    metrics: Arc<PipeMetrics>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.metrics.id()
+    }
+
+    /// Send a value to all receivers, returning the number of receivers that got it.
+    ///
+    /// With [OverflowPolicy::Backpressure] this waits until every receiver has room for the value.
+    pub async fn send(&self, data: T) -> Result<usize, SendError<T>> {
+        let data = Envelope {
+            value: data,
+            trace: trace::hop(self.id()),
+        };
+        let rc = match &self.tx {
+            SenderInner::Broadcast(tx) => tx.send(data),
+            SenderInner::Queue(queue) => self.send_queue(queue, data).await,
+        };
+        match rc {
+            Ok(count) => {
+                // A value dropped by every receiver was not delivered.
+                if count > 0 {
+                    self.metrics.sent();
+                }
+                Ok(count)
+            }
+            Err(SendError(data)) => {
+                self.metrics.send_error();
+                Err(SendError(data.value))
+            }
+        }
+    }
+
+    async fn send_queue(
+        &self,
+        queue: &Queue<Envelope<T>>,
+        data: Envelope<T>,
+    ) -> Result<usize, SendError<Envelope<T>>> {
+        let receivers = queue.receivers.lock().unwrap().clone();
+
+        let mut count = 0;
+        let mut full = 0;
+        for tx in &receivers {
+            if queue.policy == OverflowPolicy::Backpressure {
+                if tx.send(data.clone()).await.is_ok() {
+                    count += 1;
+                }
+            } else {
+                match tx.try_send(data.clone()) {
+                    Ok(()) => count += 1,
+                    // The value is dropped for this receiver, but it is still subscribed.
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        full += 1;
+                        self.metrics.lagged(1);
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {}
+                }
+            }
+        }
+
+        queue.receivers.lock().unwrap().retain(|tx| !tx.is_closed());
+        if count == 0 && full == 0 {
+            Err(SendError(data))
+        } else {
+            Ok(count)
+        }
+    }
+
+    /// Create a new receiver that gets all values sent after this call.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let rx = match &self.tx {
+            SenderInner::Broadcast(tx) => ReceiverInner::Broadcast(tx.subscribe()),
+            SenderInner::Queue(queue) => {
+                ReceiverInner::Queue(queue.subscribe(), Arc::downgrade(queue))
+            }
+        };
+        Receiver {
+            rx,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Create a handle for subscribing to the pipe that does not keep it open.
+    fn subscriber(&self) -> Subscriber<T> {
+        let inner = match &self.tx {
+            SenderInner::Broadcast(tx) => SubscriberInner::Broadcast(tx.subscribe()),
+            SenderInner::Queue(queue) => SubscriberInner::Queue(Arc::downgrade(queue)),
+        };
+        Subscriber {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let tx = match &self.tx {
+            SenderInner::Broadcast(tx) => SenderInner::Broadcast(tx.clone()),
+            SenderInner::Queue(queue) => SenderInner::Queue(queue.clone()),
+        };
+        Self {
+            tx,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+enum ReceiverInner<T> {
+    Broadcast(broadcast::Receiver<Envelope<T>>),
+    Queue(mpsc::Receiver<Envelope<T>>, Weak<Queue<Envelope<T>>>),
+}
+
+/// The receiving half of a pipe.
+///
+/// This records [metrics] for every message received, and makes the [trace] of the
+/// message the current trace of the receiving task.
+pub struct Receiver<T> {
+    rx: ReceiverInner<T>,
+    metrics: Arc<PipeMetrics>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.metrics.id()
+    }
+
+    fn record<E>(
+        &self,
+        rc: Result<Envelope<T>, E>,
+        lagged: impl Fn(&E) -> Option<u64>,
+    ) -> Result<T, E> {
+        match rc {
+            Ok(data) => {
+                self.metrics.received();
+                trace::set_current(data.trace);
+                Ok(data.value)
+            }
+            Err(err) => {
+                if let Some(dropped) = lagged(&err) {
+                    self.metrics.lagged(dropped);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Wait for the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        let rc = match &mut self.rx {
+            ReceiverInner::Broadcast(rx) => rx.recv().await,
+            ReceiverInner::Queue(rx, _) => rx.recv().await.ok_or(RecvError::Closed),
+        };
+        self.record(rc, |err| match err {
+            RecvError::Lagged(dropped) => Some(*dropped),
+            RecvError::Closed => None,
+        })
+    }
+
+    /// Create a new receiver that gets all values sent after this call.
+    ///
+    /// Unlike [Sender::subscribe] this does not keep the pipe open.
+    pub fn resubscribe(&self) -> Receiver<T> {
+        let rx = match &self.rx {
+            ReceiverInner::Broadcast(rx) => ReceiverInner::Broadcast(rx.resubscribe()),
+            ReceiverInner::Queue(_, queue) => {
+                ReceiverInner::Queue(subscribe_queue(queue), queue.clone())
+            }
+        };
+        Receiver {
+            rx,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Get the next value if one is available.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let rc = match &mut self.rx {
+            ReceiverInner::Broadcast(rx) => rx.try_recv(),
+            ReceiverInner::Queue(rx, _) => rx.try_recv().map_err(|err| match err {
+                mpsc::error::TryRecvError::Empty => TryRecvError::Empty,
+                mpsc::error::TryRecvError::Disconnected => TryRecvError::Closed,
+            }),
+        };
+        self.record(rc, |err| match err {
+            TryRecvError::Lagged(dropped) => Some(*dropped),
+            _ => None,
+        })
+    }
+}
+
+enum SubscriberInner<T> {
+    Broadcast(broadcast::Receiver<Envelope<T>>),
+    Queue(Weak<Queue<Envelope<T>>>),
+}
+
+/// Creates receivers for a pipe, without keeping the pipe open.
+///
+/// Unlike a [Receiver] this never buffers values, so it cannot hold up a sender that
+/// uses [OverflowPolicy::Backpressure].
+struct Subscriber<T> {
+    inner: SubscriberInner<T>,
+    metrics: Arc<PipeMetrics>,
+}
+
+impl<T: Clone> Subscriber<T> {
+    fn id(&self) -> PipeId {
+        self.metrics.id()
+    }
+
+    fn subscribe(&self) -> Receiver<T> {
+        let rx = match &self.inner {
+            SubscriberInner::Broadcast(rx) => ReceiverInner::Broadcast(rx.resubscribe()),
+            SubscriberInner::Queue(queue) => {
+                ReceiverInner::Queue(subscribe_queue(queue), queue.clone())
+            }
+        };
+        Receiver {
+            rx,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Clone for Subscriber<T> {
+    fn clone(&self) -> Self {
+        let inner = match &self.inner {
+            SubscriberInner::Broadcast(rx) => SubscriberInner::Broadcast(rx.resubscribe()),
+            SubscriberInner::Queue(queue) => SubscriberInner::Queue(queue.clone()),
+        };
+        Self {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Create a new channel with the given capacity and return both halves.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    channel_with_policy(capacity, OverflowPolicy::default())
+}
+
+/// Create a new channel with the given capacity and overflow policy.
+///
+/// For [OverflowPolicy::DropOldest] the capacity is shared by all receivers, otherwise
+/// every receiver gets its own queue of this size.
+pub fn channel_with_policy<T: Clone>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (Sender<T>, Receiver<T>) {
+    let tx = match policy {
+        OverflowPolicy::DropOldest => SenderInner::Broadcast(broadcast::channel(capacity).0),
+        OverflowPolicy::DropNewest | OverflowPolicy::Backpressure => {
+            SenderInner::Queue(Arc::new(Queue {
+                capacity,
+                policy,
+                receivers: Mutex::new(Vec::new()),
+            }))
+        }
+    };
+    let tx = Sender {
+        tx,
+        metrics: PipeMetrics::new(policy),
+    };
+    let rx = tx.subscribe();
+    (tx, rx)
+}
+
+/// Send a value to a pipe, but use error value that does not contain the value.
+pub async fn send<T: Clone>(tx: &Sender<T>, data: T) -> Result<()> {
+    let rc = tx.send(data).await;
 
     match rc {
         Ok(_) => Ok(()),
@@ -24,74 +378,127 @@ pub fn send<T>(tx: &broadcast::Sender<T>, data: T) -> Result<()> {
     }
 }
 
-/// Send a value to a broadcast channel and log any errors.
-pub fn send_or_log<T>(tx: &broadcast::Sender<T>, data: T) {
-    send(tx, data).unwrap_or_else(|err| {
+/// Send a value to a pipe and log any errors.
+pub async fn send_or_log<T: Clone>(tx: &Sender<T>, data: T) {
+    send(tx, data).await.unwrap_or_else(|err| {
         error!("{}", err);
     });
 }
 
-/// Receive a value from a broadcast channel and log errors
-pub async fn recv<T: Clone>(rx: &mut broadcast::Receiver<T>) -> Result<T, RecvError> {
+/// Receive a value from a pipe and log errors
+pub async fn recv<T: Clone>(rx: &mut Receiver<T>) -> Result<T, RecvError> {
     loop {
         match rx.recv().await {
             Ok(v) => break Ok(v),
             Err(err) => match err {
                 RecvError::Closed => {
-                    error!("The pipe was closed");
+                    if runtime::is_shutting_down() || reload::is_stopping() {
+                        debug!("The pipe {} was closed", rx.id());
+                    } else {
+                        error!("The pipe {} was closed", rx.id());
+                    }
                     break Err(RecvError::Closed);
                 }
-                RecvError::Lagged(_) => error!("recv failed: The pipe was lagged"),
+                RecvError::Lagged(dropped) => error!(
+                    "recv failed: The pipe {} was lagged, dropped {dropped} messages",
+                    rx.id()
+                ),
             },
         }
     }
 }
 
 /// Spawn a task and automatically monitor its execution.
+///
+/// The task cannot be restarted, so if it stops the process will exit, unless
+/// [runtime::shutdown] was called or its [reload] group was stopped. Use
+/// [supervisor::supervise] for tasks that can be restarted.
+///
+/// The returned handle gives the output of the task if it stopped without the process
+/// exiting. If the task panicked or its group was stopped, the handle reports it as
+/// cancelled instead.
 pub fn spawn<T>(future: T) -> JoinHandle<T::Output>
 where
     T: Future + Send + 'static,
     T::Output: Send + 'static,
 {
-    let task = tokio::spawn(future);
+    let guard = runtime::TaskGuard::new();
+    let stop = reload::StopHandle::current();
+    let task = tokio::spawn(trace::scope(stop.clone().run(future)));
+    let (abort_tx, abort_rx) = oneshot::channel::<AbortHandle>();
 
-    tokio::spawn(async move {
-        let rc = task.await;
-
-        match rc {
-            Ok(_rc) => error!("The thread terminated"),
-            Err(err) => error!("The thread aborted with error: {err}"),
+    let handle = tokio::spawn(async move {
+        let (rc, output) = match task.await {
+            Ok(output) => (Ok(()), output),
+            Err(err) => (Err(err), None),
         };
+        let reason = supervisor::exit_reason(rc);
+        if runtime::is_shutting_down() {
+            debug!("The task {reason} during shutdown");
+        } else if stop.is_stopped() {
+            debug!("The task {reason} after its flows were removed");
+        } else {
+            error!("The task {reason}");
+            std::process::exit(1);
+        }
+        drop(guard);
 
-        std::process::exit(1);
-    })
+        match output {
+            Some(output) => output,
+            None => {
+                if let Ok(handle) = abort_rx.await {
+                    handle.abort();
+                }
+                std::future::pending().await
+            }
+        }
+    });
+    let _ = abort_tx.send(handle.abort_handle());
+    handle
 }
 
 /// A pipe is a channel that can be used to send and receive data to multiple senders/receivers.
-pub struct Pipe<T>(broadcast::Sender<T>, Option<broadcast::Receiver<T>>);
+pub struct Pipe<T>(Sender<T>, #[allow(dead_code)] Option<Receiver<T>>);
 
 impl<T: Clone> Pipe<T> {
     #[allow(clippy::new_without_default)]
     /// Create a new pipe.
     pub fn new() -> Self {
-        let (out_tx, out_rx) = broadcast::channel(PIPE_SIZE);
-        Self(out_tx, Some(out_rx))
+        Self::new_with_policy(PIPE_SIZE, OverflowPolicy::default())
     }
 
     /// Create a new pipe with non-default size.
     pub fn new_with_size(capacity: usize) -> Self {
-        let (out_tx, out_rx) = broadcast::channel(capacity);
-        Self(out_tx, Some(out_rx))
+        Self::new_with_policy(capacity, OverflowPolicy::default())
+    }
+
+    /// Create a new pipe with non-default size and overflow policy.
+    ///
+    /// Values sent before anything subscribes to a [OverflowPolicy::DropNewest] or
+    /// [OverflowPolicy::Backpressure] pipe are lost.
+    pub fn new_with_policy(capacity: usize, policy: OverflowPolicy) -> Self {
+        let (out_tx, out_rx) = channel_with_policy(capacity, policy);
+        // A queue that nobody reads would block or drop every value, so only keep broadcast receivers.
+        let out_rx = match policy {
+            OverflowPolicy::DropOldest => Some(out_rx),
+            OverflowPolicy::DropNewest | OverflowPolicy::Backpressure => None,
+        };
+        Self(out_tx, out_rx)
+    }
+
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.0.id()
     }
 
     /// Get the underlying sender for the pipe.
-    pub fn get_tx(&self) -> broadcast::Sender<T> {
+    pub fn get_tx(&self) -> Sender<T> {
         self.0.clone()
     }
 
     /// Convert the pipe to an [RxPipe].
     pub fn to_rx_pipe(&self) -> RxPipe<T> {
-        RxPipe(self.0.clone(), self.0.subscribe())
+        RxPipe(self.0.subscriber())
     }
 
     /// Convert the pipe to an [TxPipe].
@@ -103,34 +510,124 @@ impl<T: Clone> Pipe<T> {
 
 /// A pipe that can only be used to receive data.
 ///
-/// Internally this keeps a copy of the Sender, which makes it possible to clone this object.
-pub struct RxPipe<T>(broadcast::Sender<T>, broadcast::Receiver<T>);
+/// Internally this keeps a handle for subscribing, which makes it possible to clone this
+/// object. It does not keep the pipe open, once all senders are dropped the pipe is closed.
+pub struct RxPipe<T>(Subscriber<T>);
 
 impl<T: Clone> RxPipe<T> {
-    fn new_from_sender(sender: broadcast::Sender<T>) -> Self {
-        let rx = sender.subscribe();
-        Self(sender, rx)
+    fn new_from_sender(sender: Sender<T>) -> Self {
+        Self(sender.subscriber())
+    }
+
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.0.id()
     }
 
     /// Get the underlying receiver for the pipe.
-    pub fn subscribe(&self) -> broadcast::Receiver<T> {
+    pub fn subscribe(&self) -> Receiver<T> {
         self.0.subscribe()
     }
+
+    /// Get the [OverflowPolicy] of the pipe.
+    pub fn policy(&self) -> OverflowPolicy {
+        self.0.metrics.policy()
+    }
+
+    /// Create the output pipe of an operator, with the same [OverflowPolicy] as this pipe.
+    pub(crate) fn new_output<U: Clone>(&self) -> Pipe<U> {
+        Pipe::new_with_policy(PIPE_SIZE, self.policy())
+    }
+
+    /// Name the node(s) that write to this pipe.
+    ///
+    /// The name is used when exporting the flow graph from the [registry].
+    pub fn named(self, name: &str) -> Self {
+        registry::set_producer_name(self.id(), name);
+        self
+    }
 }
 
-impl<T> Clone for RxPipe<T> {
+impl<T: Clone> Clone for RxPipe<T> {
     fn clone(&self) -> Self {
-        Self(self.0.clone(), self.0.subscribe())
+        Self(self.0.clone())
     }
 }
 
 /// A pipe that can only be used to send data.
 #[derive(Clone)]
-pub struct TxPipe<T>(broadcast::Sender<T>);
+pub struct TxPipe<T>(Sender<T>);
 
 impl<T: Clone> TxPipe<T> {
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.0.id()
+    }
+
     /// Get the underlying sender for the pipe.
-    pub fn get_tx(&self) -> broadcast::Sender<T> {
+    pub fn get_tx(&self) -> Sender<T> {
         self.0.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let (tx, mut rx) = channel_with_policy(2, OverflowPolicy::DropNewest);
+
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        tx.send(3).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 1);
+        assert_eq!(rx.recv().await.unwrap(), 2);
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+
+        drop(tx);
+        assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (tx, mut rx) = channel_with_policy(1, OverflowPolicy::Backpressure);
+        let mut other = rx.resubscribe();
+
+        tx.send(1).await.unwrap();
+        let blocked = timeout(Duration::from_millis(10), tx.send(2)).await;
+        assert!(blocked.is_err());
+
+        let sender = tokio::spawn(async move {
+            tx.send(2).await.unwrap();
+            tx.send(3).await.unwrap();
+        });
+        for v in 1..=3 {
+            assert_eq!(rx.recv().await.unwrap(), v);
+            assert_eq!(other.recv().await.unwrap(), v);
+        }
+        sender.await.unwrap();
+        assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_rx_pipe_does_not_block_backpressure() {
+        let pipe = Pipe::new_with_policy(1, OverflowPolicy::Backpressure);
+        let rx_pipe = pipe.to_rx_pipe();
+        let mut rx = rx_pipe.subscribe();
+        let tx = pipe.get_tx();
+        drop(pipe);
+
+        tx.send(1).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 1);
+        tx.send(2).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), 2);
+
+        drop(tx);
+        assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
+        let mut late = rx_pipe.subscribe();
+        assert!(matches!(late.recv().await, Err(RecvError::Closed)));
+    }
+}
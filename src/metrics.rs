@@ -0,0 +1,196 @@
+//! Metrics for pipes, exported in Prometheus text format.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::registry::{self, PipeId};
+use crate::OverflowPolicy;
+
+/// Counters for a single pipe.
+#[derive(Debug)]
+pub(crate) struct PipeMetrics {
+    id: PipeId,
+    policy: OverflowPolicy,
+    sent: AtomicU64,
+    received: AtomicU64,
+    lag_events: AtomicU64,
+    dropped: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+static PIPES: Mutex<Vec<Weak<PipeMetrics>>> = Mutex::new(Vec::new());
+
+impl PipeMetrics {
+    /// Create metrics for a new pipe and make them available for export.
+    pub(crate) fn new(policy: OverflowPolicy) -> Arc<Self> {
+        let metrics = Arc::new(PipeMetrics {
+            id: PipeId::new(),
+            policy,
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            lag_events: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+        });
+        PIPES.lock().unwrap().push(Arc::downgrade(&metrics));
+        metrics
+    }
+
+    pub(crate) fn id(&self) -> PipeId {
+        self.id
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub(crate) fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn lagged(&self, dropped: u64) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_counter(
+    out: &mut String,
+    pipes: &[(String, Arc<PipeMetrics>)],
+    name: &str,
+    help: &str,
+    value: impl Fn(&PipeMetrics) -> &AtomicU64,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} counter").unwrap();
+    for (labels, metrics) in pipes {
+        let value = value(metrics).load(Ordering::Relaxed);
+        writeln!(out, "{name}{{{labels}}} {value}").unwrap();
+    }
+}
+
+/// Export the metrics for all pipes in Prometheus text format.
+///
+/// Every pipe is labelled with its id, the node(s) that write to it, and its [OverflowPolicy].
+/// Pipes that no longer exist, for example because their flows were [crate::reload]ed, are
+/// left out.
+pub fn to_prometheus() -> String {
+    let mut pipes = PIPES.lock().unwrap();
+    pipes.retain(|metrics| metrics.strong_count() > 0);
+    let pipes: Vec<_> = pipes
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|metrics| {
+            let node = registry::producer_label(metrics.id).unwrap_or_default();
+            let labels = format!(
+                "pipe=\"{}\",node=\"{}\",policy=\"{}\"",
+                metrics.id,
+                escape_label(&node),
+                metrics.policy
+            );
+            (labels, metrics)
+        })
+        .collect();
+
+    let mut out = String::new();
+    write_counter(
+        &mut out,
+        &pipes,
+        "robotica_pipe_sent_total",
+        "Messages sent to the pipe.",
+        |m| &m.sent,
+    );
+    write_counter(
+        &mut out,
+        &pipes,
+        "robotica_pipe_received_total",
+        "Messages received from the pipe, summed over all receivers.",
+        |m| &m.received,
+    );
+    write_counter(
+        &mut out,
+        &pipes,
+        "robotica_pipe_lag_events_total",
+        "Number of times a receiver lagged behind the pipe, or was too full to take a message.",
+        |m| &m.lag_events,
+    );
+    write_counter(
+        &mut out,
+        &pipes,
+        "robotica_pipe_dropped_total",
+        "Messages dropped because a receiver could not keep up with the pipe.",
+        |m| &m.dropped,
+    );
+    write_counter(
+        &mut out,
+        &pipes,
+        "robotica_pipe_send_errors_total",
+        "Messages that could not be sent because the pipe had no receivers.",
+        |m| &m.send_errors,
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::register_named_node;
+    use crate::{channel, channel_with_policy};
+
+    #[tokio::test]
+    async fn test_to_prometheus() {
+        let (tx, mut rx) = channel(2);
+        register_named_node("subscribe", "metrics/\"test\"", &[], &[tx.id()]);
+        let labels = format!(
+            "pipe=\"{}\",node=\"subscribe: metrics/\\\"test\\\"\",policy=\"drop_oldest\"",
+            tx.id()
+        );
+
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        tx.send(3).await.unwrap();
+        let v = rx.recv().await;
+        assert!(v.is_err());
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, 2);
+        drop(rx);
+        assert!(tx.send(4).await.is_err());
+
+        let text = to_prometheus();
+        assert!(text.contains(&format!("robotica_pipe_sent_total{{{labels}}} 3\n")));
+        assert!(text.contains(&format!("robotica_pipe_received_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!("robotica_pipe_lag_events_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!("robotica_pipe_dropped_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!("robotica_pipe_send_errors_total{{{labels}}} 1\n")));
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_is_counted() {
+        let (tx, mut rx) = channel_with_policy(1, OverflowPolicy::DropNewest);
+        let labels = format!("pipe=\"{}\",node=\"\",policy=\"drop_newest\"", tx.id());
+
+        assert_eq!(tx.send(1).await.unwrap(), 1);
+        assert_eq!(tx.send(2).await.unwrap(), 0);
+        assert_eq!(rx.recv().await.unwrap(), 1);
+
+        let text = to_prometheus();
+        assert!(text.contains(&format!("robotica_pipe_sent_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!("robotica_pipe_dropped_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!("robotica_pipe_send_errors_total{{{labels}}} 0\n")));
+    }
+}
@@ -0,0 +1,254 @@
+//! Save the state of nodes, so it survives a restart.
+//!
+//! Persistence is opt-in. Stateful operators have `_persisted` variants that take a stable id,
+//! which must be unique and must not change between restarts. State is kept in memory, and
+//! written to a JSON file periodically, and again by [crate::runtime::shutdown_and_wait]
+//! once all tasks have finished. Saved values are only serialized when they are written.
+//!
+//! If [start] is not called, persisted operators behave like the normal ones.
+use anyhow::{Context, Result};
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::{select, task, time};
+
+use crate::reload;
+use crate::runtime::wait_for_shutdown;
+
+type Serializer = Box<dyn FnOnce() -> serde_json::Result<serde_json::Value> + Send>;
+
+/// A persisted value, which is serialized when it is next needed.
+enum Entry {
+    Serialized(serde_json::Value),
+    Dirty(Serializer),
+}
+
+struct Store {
+    path: Option<PathBuf>,
+    values: BTreeMap<String, Entry>,
+    claimed: BTreeSet<String>,
+    dirty: bool,
+}
+
+impl Store {
+    /// Serialize the entry for `id` if it is dirty, dropping it if that fails.
+    fn serialize(&mut self, id: &str) -> Option<&serde_json::Value> {
+        if let Some(Entry::Dirty(_)) = self.values.get(id) {
+            if let Some(Entry::Dirty(serializer)) = self.values.remove(id) {
+                match serializer() {
+                    Ok(value) => {
+                        self.values.insert(id.to_string(), Entry::Serialized(value));
+                    }
+                    Err(err) => error!("Cannot persist the state for {id}: {err}"),
+                }
+            }
+        }
+        match self.values.get(id)? {
+            Entry::Serialized(value) => Some(value),
+            Entry::Dirty(_) => None,
+        }
+    }
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store {
+    path: None,
+    values: BTreeMap::new(),
+    claimed: BTreeSet::new(),
+    dirty: false,
+});
+
+/// Held while writing the file, so two flushes do not write it at the same time.
+static WRITING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, serde_json::Value>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let text = fs::read_to_string(path)?;
+    let values = serde_json::from_str(&text)?;
+    Ok(values)
+}
+
+/// Restore state from `path`, and write it back every `flush_interval`.
+///
+/// This must be called before creating any flows that use persisted operators. Values that
+/// were saved before this was called are kept.
+pub fn start(path: impl Into<PathBuf>, flush_interval: Duration) -> Result<()> {
+    let path = path.into();
+    let values =
+        read_file(&path).with_context(|| format!("Cannot read state from {}", path.display()))?;
+    info!("Restored {} values from {}", values.len(), path.display());
+
+    {
+        let mut store = STORE.lock().unwrap();
+        store.path = Some(path);
+        for (id, value) in values {
+            store.values.entry(id).or_insert(Entry::Serialized(value));
+        }
+    }
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(flush_interval);
+        loop {
+            select! {
+                _ = interval.tick() => flush().await,
+                _ = wait_for_shutdown() => { break; }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Claim an id for a node, warning if another node already uses it.
+pub(crate) fn claim(id: &str) {
+    let mut store = STORE.lock().unwrap();
+    if !store.claimed.insert(id.to_string()) {
+        warn!("The persisted id {id} is used by more then one node");
+    }
+    drop(store);
+    reload::add_persisted(id);
+}
+
+/// Release the ids of nodes that were removed, so they can be claimed again.
+pub(crate) fn release(ids: &[String]) {
+    let mut store = STORE.lock().unwrap();
+    for id in ids {
+        store.claimed.remove(id);
+    }
+}
+
+/// Get the saved value for `id`, if there is one.
+pub fn load<V: DeserializeOwned>(id: &str) -> Option<V> {
+    let mut store = STORE.lock().unwrap();
+    let value = store.serialize(id)?.clone();
+    drop(store);
+
+    match serde_json::from_value(value) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            warn!("Cannot restore the persisted state for {id}: {err}");
+            None
+        }
+    }
+}
+
+/// Save a new value for `id`.
+///
+/// The value is only serialized when it is written to disk on the next flush.
+pub fn save<V: Serialize + Clone + Send + 'static>(id: &str, value: &V) {
+    let value = value.clone();
+    let mut store = STORE.lock().unwrap();
+    store.values.insert(
+        id.to_string(),
+        Entry::Dirty(Box::new(move || serde_json::to_value(value))),
+    );
+    store.dirty = true;
+}
+
+/// Remove the saved value for `id`.
+pub fn remove(id: &str) {
+    let mut store = STORE.lock().unwrap();
+    if store.values.remove(id).is_some() {
+        store.dirty = true;
+    }
+}
+
+/// Write all values to disk now, if anything changed.
+///
+/// The file is written on a blocking thread, and replaced atomically, so a crash while
+/// writing leaves the old state behind.
+pub async fn flush() {
+    let _writing = WRITING.lock().await;
+
+    let (path, text) = {
+        let mut store = STORE.lock().unwrap();
+        let Some(path) = store.path.clone() else {
+            return;
+        };
+        if !store.dirty {
+            return;
+        }
+        let ids: Vec<String> = store.values.keys().cloned().collect();
+        for id in &ids {
+            store.serialize(id);
+        }
+        let values: BTreeMap<&String, &serde_json::Value> = store
+            .values
+            .iter()
+            .filter_map(|(id, entry)| match entry {
+                Entry::Serialized(value) => Some((id, value)),
+                Entry::Dirty(_) => None,
+            })
+            .collect();
+        let text = match serde_json::to_string_pretty(&values) {
+            Ok(text) => text,
+            Err(err) => {
+                error!("Cannot serialize the persisted state: {err}");
+                return;
+            }
+        };
+        store.dirty = false;
+        (path, text)
+    };
+
+    let tmp_path = path.with_extension("tmp");
+    let write_path = path.clone();
+    let rc = task::spawn_blocking(move || {
+        fs::write(&tmp_path, text).and_then(|_| fs::rename(&tmp_path, &write_path))
+    })
+    .await
+    .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+    match rc {
+        Ok(()) => debug!("Saved state to {}", path.display()),
+        Err(err) => {
+            error!("Cannot save state to {}: {err}", path.display());
+            STORE.lock().unwrap().dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        save("test_save_and_load", &(Some(10), 20));
+        let v: Option<(Option<i32>, i32)> = load("test_save_and_load");
+        assert_eq!(v, Some((Some(10), 20)));
+
+        let v: Option<String> = load("test_save_and_load");
+        assert_eq!(v, None);
+
+        remove("test_save_and_load");
+        let v: Option<(Option<i32>, i32)> = load("test_save_and_load");
+        assert_eq!(v, None);
+    }
+
+    #[tokio::test]
+    async fn test_restore_and_flush() {
+        let path = std::env::temp_dir().join(format!("robotica-state-{}.json", std::process::id()));
+        fs::write(&path, r#"{"test_restored": [null, 10]}"#).unwrap();
+
+        start(&path, Duration::from_secs(60)).unwrap();
+        let v: Option<(Option<i32>, i32)> = load("test_restored");
+        assert_eq!(v, Some((None, 10)));
+
+        save("test_flushed", &42);
+        flush().await;
+
+        let values = read_file(&path).unwrap();
+        assert_eq!(values.get("test_flushed"), Some(&serde_json::json!(42)));
+        assert_eq!(
+            values.get("test_restored"),
+            Some(&serde_json::json!([null, 10]))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Registry of the nodes and pipes that make up the flow graph.
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::reload;
+
+/// Unique id for a pipe.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PipeId(usize);
+
+impl PipeId {
+    pub(crate) fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(1);
+        PipeId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Display for PipeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "p{}", self.0)
+    }
+}
+
+/// Unique id for a node.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct NodeId(usize);
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "n{}", self.0)
+    }
+}
+
+/// A node in the flow graph, usually the task behind an operator.
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// The id of the node.
+    pub id: NodeId,
+    /// What type of node this is, e.g. `map` or `filter`.
+    pub kind: String,
+    /// Optional human readable name for the node.
+    pub name: Option<String>,
+    /// Pipes this node reads from.
+    pub inputs: Vec<PipeId>,
+    /// Pipes this node writes to.
+    pub outputs: Vec<PipeId>,
+}
+
+impl Node {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}: {}", self.kind, name),
+            None => self.kind.clone(),
+        }
+    }
+}
+
+struct Registry {
+    next_id: usize,
+    nodes: BTreeMap<NodeId, Node>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 1,
+    nodes: BTreeMap::new(),
+});
+
+/// Register a new node that reads from `inputs` and writes to `outputs`.
+pub fn register_node(kind: &str, inputs: &[PipeId], outputs: &[PipeId]) -> NodeId {
+    let mut registry = REGISTRY.lock().unwrap();
+    let id = NodeId(registry.next_id);
+    registry.next_id += 1;
+
+    let node = Node {
+        id,
+        kind: kind.to_string(),
+        name: None,
+        inputs: inputs.to_vec(),
+        outputs: outputs.to_vec(),
+    };
+    registry.nodes.insert(id, node);
+    reload::add_node(id);
+    id
+}
+
+/// Remove nodes that no longer exist.
+pub(crate) fn remove_nodes(ids: &[NodeId]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    for id in ids {
+        registry.nodes.remove(id);
+    }
+}
+
+/// Register a new node with a name.
+pub fn register_named_node(
+    kind: &str,
+    name: &str,
+    inputs: &[PipeId],
+    outputs: &[PipeId],
+) -> NodeId {
+    let id = register_node(kind, inputs, outputs);
+    set_node_name(id, name);
+    id
+}
+
+/// Set the name of a node.
+pub fn set_node_name(id: NodeId, name: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some(node) = registry.nodes.get_mut(&id) {
+        node.name = Some(name.to_string());
+    }
+}
+
+/// Set the name of every node that writes to a pipe.
+pub fn set_producer_name(pipe: PipeId, name: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .nodes
+        .values_mut()
+        .filter(|node| node.outputs.contains(&pipe))
+        .for_each(|node| node.name = Some(name.to_string()));
+}
+
+/// Get a label describing the node(s) that write to a pipe.
+pub(crate) fn producer_label(pipe: PipeId) -> Option<String> {
+    let registry = REGISTRY.lock().unwrap();
+    let labels: Vec<_> = registry
+        .nodes
+        .values()
+        .filter(|node| node.outputs.contains(&pipe))
+        .map(|node| node.label())
+        .collect();
+
+    if labels.is_empty() {
+        None
+    } else {
+        Some(labels.join(", "))
+    }
+}
+
+/// Get a copy of every registered node.
+pub fn nodes() -> Vec<Node> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.nodes.values().cloned().collect()
+}
+
+/// Get all edges between nodes, along with the pipe that connects them.
+fn edges(nodes: &[Node]) -> Vec<(NodeId, NodeId, PipeId)> {
+    let mut producers: BTreeMap<PipeId, Vec<NodeId>> = BTreeMap::new();
+    for node in nodes {
+        for pipe in &node.outputs {
+            producers.entry(*pipe).or_default().push(node.id);
+        }
+    }
+
+    let mut edges = Vec::new();
+    for node in nodes {
+        for pipe in &node.inputs {
+            if let Some(sources) = producers.get(pipe) {
+                for source in sources {
+                    edges.push((*source, node.id, *pipe));
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// Export the flow graph in Graphviz DOT format.
+pub fn to_dot() -> String {
+    let nodes = nodes();
+    let mut out = String::from("digraph flows {\n");
+
+    for node in &nodes {
+        let label = node.label().replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(out, "    {} [label=\"{}\"];", node.id, label).unwrap();
+    }
+    for (from, to, pipe) in edges(&nodes) {
+        writeln!(out, "    {from} -> {to} [label=\"{pipe}\"];").unwrap();
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+/// Export the flow graph as a Mermaid diagram.
+pub fn to_mermaid() -> String {
+    let nodes = nodes();
+    let mut out = String::from("graph TD\n");
+
+    for node in &nodes {
+        let label = node.label().replace('"', "#quot;");
+        writeln!(out, "    {}[\"{}\"]", node.id, label).unwrap();
+    }
+    for (from, to, _) in edges(&nodes) {
+        writeln!(out, "    {from} --> {to}").unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let a = PipeId::new();
+        let b = PipeId::new();
+
+        let source = register_named_node("subscribe", "test/\"topic\"", &[], &[a]);
+        let map = register_node("map", &[a], &[b]);
+        let sink = register_node("null", &[b], &[]);
+
+        let dot = to_dot();
+        assert!(dot.contains(&format!(
+            "{source} [label=\"subscribe: test/\\\"topic\\\"\"];"
+        )));
+        assert!(dot.contains(&format!("{source} -> {map} [label=\"{a}\"];")));
+        assert!(dot.contains(&format!("{map} -> {sink} [label=\"{b}\"];")));
+
+        let mermaid = to_mermaid();
+        assert!(mermaid.contains(&format!("{source}[\"subscribe: test/#quot;topic#quot;\"]")));
+        assert!(mermaid.contains(&format!("{source} --> {map}")));
+        assert!(mermaid.contains(&format!("{map} --> {sink}")));
+    }
+
+    #[test]
+    fn test_set_producer_name() {
+        let a = PipeId::new();
+        let node = register_node("map", &[], &[a]);
+        set_producer_name(a, "battery level");
+
+        let node = nodes().into_iter().find(|n| n.id == node).unwrap();
+        assert_eq!(node.name.as_deref(), Some("battery level"));
+    }
+}
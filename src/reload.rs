@@ -0,0 +1,287 @@
+//! Rebuild parts of the flow graph without restarting.
+//!
+//! Flows are built in named groups with [Flows::group]. Every task spawned while building a
+//! group belongs to it, and is stopped when the group is rebuilt with different parameters,
+//! or removed. Pipes the group reads from, such as MQTT subscriptions, stay up, as do all
+//! the other groups.
+//!
+//! Stateful operators in a rebuilt group start again from scratch, and only see the next
+//! message sent to their inputs. Persisted operators restore their saved state instead.
+use log::*;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::{self, Future};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::select;
+use tokio::sync::{watch, Notify};
+
+use crate::persist;
+use crate::registry::{self, NodeId};
+
+/// The tasks and nodes of one group of flows.
+struct Group {
+    stop: watch::Sender<bool>,
+    nodes: Mutex<Vec<NodeId>>,
+    persisted: Mutex<Vec<String>>,
+}
+
+impl Group {
+    fn new() -> Self {
+        Group {
+            stop: watch::channel(false).0,
+            nodes: Mutex::new(Vec::new()),
+            persisted: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn stop(&self) {
+        self.stop.send_replace(true);
+        let nodes = self.nodes.lock().unwrap();
+        registry::remove_nodes(&nodes);
+        let persisted = self.persisted.lock().unwrap();
+        persist::release(&persisted);
+    }
+}
+
+thread_local! {
+    static BUILDING: RefCell<Option<Arc<Group>>> = const { RefCell::new(None) };
+}
+
+tokio::task_local! {
+    static STOPPING: watch::Receiver<bool>;
+}
+
+/// Record a node that belongs to the group that is being built, if any.
+pub(crate) fn add_node(id: NodeId) {
+    BUILDING.with(|building| {
+        if let Some(group) = &*building.borrow() {
+            group.nodes.lock().unwrap().push(id);
+        }
+    });
+}
+
+/// Record a persisted id that belongs to the group that is being built, if any.
+pub(crate) fn add_persisted(id: &str) {
+    BUILDING.with(|building| {
+        if let Some(group) = &*building.borrow() {
+            group.persisted.lock().unwrap().push(id.to_string());
+        }
+    });
+}
+
+/// Is the group of the current task being stopped?
+pub(crate) fn is_stopping() -> bool {
+    STOPPING.try_with(|stop| *stop.borrow()).unwrap_or(false)
+}
+
+async fn wait_for_stop(rx: &mut watch::Receiver<bool>) -> bool {
+    rx.wait_for(|stop| *stop).await.is_ok()
+}
+
+/// Stops a task when the group it was spawned in is stopped.
+#[derive(Clone)]
+pub(crate) struct StopHandle(Option<watch::Receiver<bool>>);
+
+impl StopHandle {
+    /// Get the handle for the group that is being built, if any.
+    pub(crate) fn current() -> Self {
+        let rx = BUILDING.with(|building| {
+            building
+                .borrow()
+                .as_ref()
+                .map(|group| group.stop.subscribe())
+        });
+        StopHandle(rx)
+    }
+
+    /// Has the group been stopped?
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.0.as_ref().is_some_and(|rx| *rx.borrow())
+    }
+
+    /// Run `future` until it finishes, or return `None` if the group is stopped first.
+    pub(crate) async fn run<F: Future>(self, future: F) -> Option<F::Output> {
+        let Some(mut rx) = self.0 else {
+            return Some(future.await);
+        };
+        let stop = rx.clone();
+        STOPPING
+            .scope(stop, async move {
+                select! {
+                    output = future => Some(output),
+                    stopped = wait_for_stop(&mut rx) => {
+                        // If the group was dropped without being stopped, keep running.
+                        if !stopped {
+                            future::pending::<()>().await;
+                        }
+                        None
+                    }
+                }
+            })
+            .await
+    }
+}
+
+/// Builds a group for the duration of a call, restoring the previous group afterwards.
+struct Building(Option<Arc<Group>>);
+
+impl Building {
+    fn enter(group: Option<Arc<Group>>) -> Self {
+        Building(BUILDING.with(|building| building.replace(group)))
+    }
+}
+
+/// Create nodes and tasks that do not belong to the group that is being built.
+pub(crate) fn outside_group<R>(f: impl FnOnce() -> R) -> R {
+    let _building = Building::enter(None);
+    f()
+}
+
+impl Drop for Building {
+    fn drop(&mut self) {
+        BUILDING.with(|building| *building.borrow_mut() = self.0.take());
+    }
+}
+
+struct Built {
+    params: Box<dyn Any + Send>,
+    group: Arc<Group>,
+}
+
+/// A set of groups of flows that can be rebuilt.
+#[derive(Default)]
+pub struct Flows {
+    groups: BTreeMap<String, Built>,
+    seen: BTreeSet<String>,
+}
+
+impl Flows {
+    /// Create an empty set of flows.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the group `name` by calling `build` with `params`.
+    ///
+    /// If the group was already built with the same parameters it keeps running, and `build`
+    /// is not called. Otherwise the old group is stopped before building the new one.
+    pub fn group<P: PartialEq + Send + 'static>(
+        &mut self,
+        name: &str,
+        params: P,
+        build: impl FnOnce(&P),
+    ) {
+        self.seen.insert(name.to_string());
+
+        if let Some(built) = self.groups.get(name) {
+            if built.params.downcast_ref::<P>() == Some(&params) {
+                return;
+            }
+        }
+
+        match self.groups.remove(name) {
+            Some(old) => {
+                info!("Rebuilding the flows for {name}");
+                old.group.stop();
+            }
+            None => info!("Building the flows for {name}"),
+        }
+
+        let group = Arc::new(Group::new());
+        {
+            let _building = Building::enter(Some(group.clone()));
+            build(&params);
+        }
+
+        let params = Box::new(params);
+        self.groups
+            .insert(name.to_string(), Built { params, group });
+    }
+
+    /// Stop every group that was not built since the last call.
+    pub fn finish(&mut self) {
+        let seen = std::mem::take(&mut self.seen);
+        self.groups.retain(|name, built| {
+            let keep = seen.contains(name);
+            if !keep {
+                info!("Removing the flows for {name}");
+                built.group.stop();
+            }
+            keep
+        });
+    }
+
+    /// Get the names of all groups.
+    pub fn names(&self) -> Vec<String> {
+        self.groups.keys().cloned().collect()
+    }
+}
+
+fn requests() -> &'static Notify {
+    static REQUESTS: OnceLock<Notify> = OnceLock::new();
+    REQUESTS.get_or_init(Notify::new)
+}
+
+/// Ask for the flows to be reloaded, see [wait_for_reload].
+pub fn request_reload() {
+    requests().notify_one();
+}
+
+/// Wait until a reload is requested with [request_reload].
+pub async fn wait_for_reload() {
+    requests().notified().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime, Pipe};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_rebuild_changed_group() {
+        let input = Pipe::new();
+        let tx = input.get_tx();
+        let rx_pipe = input.to_rx_pipe();
+        let mut flows = Flows::new();
+        let mut outputs = Vec::new();
+
+        let mut build = |flows: &mut Flows, factor: i32| {
+            flows.group("test/double", factor, |factor| {
+                let factor = *factor;
+                outputs.push(rx_pipe.map(move |v: i32| v * factor).subscribe());
+            });
+            flows.group("test/static", (), |_| {});
+            flows.finish();
+        };
+
+        build(&mut flows, 2);
+        build(&mut flows, 2);
+        build(&mut flows, 3);
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(flows.names(), vec!["test/double", "test/static"]);
+
+        tx.send(10).await.unwrap();
+
+        let mut new = outputs.pop().unwrap();
+        let mut old = outputs.pop().unwrap();
+        assert_eq!(new.recv().await.unwrap(), 30);
+        // The old task may still see the value before it stops, but its output gets closed.
+        while timeout(Duration::from_secs(1), old.recv())
+            .await
+            .unwrap()
+            .is_ok()
+        {}
+        assert!(!runtime::is_shutting_down());
+    }
+
+    #[tokio::test]
+    async fn test_request_reload() {
+        request_reload();
+        timeout(Duration::from_secs(1), wait_for_reload())
+            .await
+            .unwrap();
+    }
+}
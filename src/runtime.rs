@@ -0,0 +1,105 @@
+//! Keep track of running tasks and shut them down gracefully.
+//!
+//! When shutdown is requested, sources stop producing values and drop their pipes. Filters
+//! then drain whatever is left in their input pipes, and stop once their inputs are closed.
+//!
+//! The runtime is a process-wide singleton, not a value that can be created more then once.
+//! Every task started with [crate::spawn] or [crate::supervisor::supervise] is counted here,
+//! and shutdown cannot be undone. Tests that request shutdown must run in a process of their
+//! own, such as a separate file in `tests/`, so they do not affect other tests.
+use log::*;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+use crate::persist;
+
+struct State {
+    shutdown: watch::Sender<bool>,
+    tasks: watch::Sender<usize>,
+}
+
+fn state() -> &'static State {
+    static STATE: OnceLock<State> = OnceLock::new();
+    STATE.get_or_init(|| State {
+        shutdown: watch::channel(false).0,
+        tasks: watch::channel(0).0,
+    })
+}
+
+/// Guard that marks a task as running until it is dropped.
+pub(crate) struct TaskGuard(());
+
+impl TaskGuard {
+    pub(crate) fn new() -> Self {
+        state().tasks.send_modify(|tasks| *tasks += 1);
+        TaskGuard(())
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        state().tasks.send_modify(|tasks| *tasks -= 1);
+    }
+}
+
+/// Ask all sources to stop so the flows can drain.
+pub fn shutdown() {
+    state().shutdown.send_replace(true);
+}
+
+/// Has shutdown been requested?
+pub fn is_shutting_down() -> bool {
+    *state().shutdown.borrow()
+}
+
+/// Wait until shutdown is requested.
+pub async fn wait_for_shutdown() {
+    let mut rx = state().shutdown.subscribe();
+    // The sender lives in a static, so this can never fail.
+    let _ = rx.wait_for(|shutdown| *shutdown).await;
+}
+
+/// Number of tasks that are still running.
+pub fn running_tasks() -> usize {
+    *state().tasks.borrow()
+}
+
+/// Wait until every task has finished.
+pub async fn wait_for_tasks() {
+    let mut rx = state().tasks.subscribe();
+    let _ = rx.wait_for(|tasks| *tasks == 0).await;
+}
+
+/// Request shutdown, then wait up to `grace` for all tasks to finish.
+///
+/// Persisted state is saved once the tasks have finished.
+pub async fn shutdown_and_wait(grace: Duration) {
+    shutdown();
+    match timeout(grace, wait_for_tasks()).await {
+        Ok(()) => info!("All tasks finished"),
+        Err(_) => warn!(
+            "Timeout waiting for tasks to finish, {} still running",
+            running_tasks()
+        ),
+    }
+    persist::flush().await;
+}
+
+/// Wait for SIGTERM or SIGINT, then shut down gracefully.
+///
+/// Tasks get up to `grace` to finish before this returns.
+pub async fn run_until_signal(grace: Duration) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Cannot install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Cannot install SIGINT handler");
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        _ = wait_for_shutdown() => info!("Shutdown requested"),
+    }
+
+    shutdown_and_wait(grace).await;
+}
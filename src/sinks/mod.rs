@@ -1,9 +1,8 @@
 //! Sinks take one/more inputs and produce now outputs.
-use tokio::sync::broadcast;
+use crate::registry::register_node;
+use crate::{recv, spawn, Receiver, RxPipe};
 
-use crate::{recv, spawn, RxPipe};
-
-fn null<T: Send + Clone + 'static>(mut input: broadcast::Receiver<T>) {
+fn null<T: Send + Clone + 'static>(mut input: Receiver<T>) {
     spawn(async move {
         while (recv(&mut input).await).is_ok() {
             // do nothing
@@ -14,6 +13,7 @@ fn null<T: Send + Clone + 'static>(mut input: broadcast::Receiver<T>) {
 impl<T: Send + Clone + 'static> RxPipe<T> {
     /// Send the data to a black hole.
     pub fn null(&self) {
+        register_node("null", &[self.id()], &[]);
         null(self.subscribe());
     }
 }
@@ -21,14 +21,15 @@ impl<T: Send + Clone + 'static> RxPipe<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channel;
 
     #[tokio::test]
     async fn test_null() {
-        let (tx, rx) = broadcast::channel(1);
+        let (tx, rx) = channel(1);
         null(rx);
-        tx.send(10).unwrap();
-        tx.send(10).unwrap();
-        tx.send(10).unwrap();
-        tx.send(10).unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(10).await.unwrap();
+        tx.send(10).await.unwrap();
     }
 }
@@ -0,0 +1,268 @@
+//! Leader election over MQTT, so a standby copy can take over.
+//!
+//! Every copy subscribes to a retained lock topic. The leader publishes a heartbeat to it,
+//! and the broker publishes the leader's last will if it disconnects without saying goodbye.
+//! A standby claims the lock when it is released, or when the leader's heartbeat stops. If
+//! two copies claim it at the same time, the last claim the broker receives wins.
+//!
+//! Every copy has a last will, so a release only counts if it comes from the current leader.
+//! A copy that starts after a standby went away cannot tell, so it waits for the heartbeat.
+//!
+//! Only the leader publishes outgoing messages, a standby runs all the flows but discards
+//! them, so it is ready to take over at any time.
+use log::*;
+use paho_mqtt::Message;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval};
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum LockState {
+    Alive,
+    Released,
+}
+
+/// The payload of the lock topic.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+struct Lock {
+    id: String,
+    state: LockState,
+}
+
+/// The state of the leader election for one copy.
+pub(crate) struct Election {
+    topic: String,
+    id: String,
+    timeout: Duration,
+    interval: Interval,
+    leader: Option<String>,
+    last_seen: Instant,
+    is_leader: Arc<AtomicBool>,
+}
+
+impl Election {
+    /// Elect a leader using `topic`, the leader sends a heartbeat every `heartbeat`.
+    ///
+    /// A leader is considered gone after missing three heartbeats.
+    pub(crate) fn new(
+        topic: &str,
+        id: &str,
+        heartbeat: Duration,
+        is_leader: Arc<AtomicBool>,
+    ) -> Self {
+        is_leader.store(false, Ordering::Relaxed);
+        Election {
+            topic: topic.to_string(),
+            id: id.to_string(),
+            timeout: heartbeat * 3,
+            interval: time::interval(heartbeat),
+            leader: None,
+            last_seen: Instant::now(),
+            is_leader,
+        }
+    }
+
+    /// Get the lock topic.
+    pub(crate) fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.as_deref() == Some(self.id.as_str())
+    }
+
+    fn message(&self, state: LockState) -> Message {
+        let lock = Lock {
+            id: self.id.clone(),
+            state,
+        };
+        let payload = serde_json::to_string(&lock).unwrap();
+        Message::new_retained(&self.topic, payload, 1)
+    }
+
+    /// The message the broker publishes if we disconnect unexpectedly.
+    pub(crate) fn last_will(&self) -> Message {
+        self.message(LockState::Released)
+    }
+
+    /// The message to release the lock on shutdown, if we have it.
+    pub(crate) fn release(&self) -> Option<Message> {
+        self.is_leader().then(|| self.message(LockState::Released))
+    }
+
+    fn set_leader(&mut self, leader: Option<String>) {
+        if self.leader == leader {
+            return;
+        }
+        self.leader = leader;
+        let is_leader = self.is_leader();
+        if is_leader != self.is_leader.swap(is_leader, Ordering::Relaxed) {
+            if is_leader {
+                warn!("Became the leader, publishing outgoing messages");
+            } else {
+                warn!("Became a standby, discarding outgoing messages");
+            }
+        }
+        info!("The leader is now {:?}", self.leader);
+    }
+
+    /// Handle a message received on the lock topic, and return the message to send back.
+    pub(crate) fn handle(&mut self, payload: &str, now: Instant) -> Option<Message> {
+        let lock: Option<Lock> = match payload {
+            "" => None,
+            payload => serde_json::from_str(payload)
+                .map_err(|err| error!("Invalid lock {payload}: {err}"))
+                .ok(),
+        };
+
+        match lock {
+            Some(Lock {
+                id,
+                state: LockState::Alive,
+            }) => {
+                self.last_seen = now;
+                self.set_leader(Some(id));
+                None
+            }
+            Some(Lock {
+                id,
+                state: LockState::Released,
+            }) if self.leader.as_ref() != Some(&id) => None,
+            _ => {
+                self.set_leader(None);
+                self.last_seen = now;
+                Some(self.message(LockState::Alive))
+            }
+        }
+    }
+
+    /// Return the heartbeat if we are the leader, or a claim if the leader is gone.
+    pub(crate) fn tick(&mut self, now: Instant) -> Option<Message> {
+        if self.is_leader() {
+            Some(self.message(LockState::Alive))
+        } else if now.duration_since(self.last_seen) >= self.timeout {
+            if let Some(leader) = &self.leader {
+                warn!("The leader {leader} stopped sending heartbeats");
+            }
+            self.set_leader(None);
+            self.last_seen = now;
+            Some(self.message(LockState::Alive))
+        } else {
+            None
+        }
+    }
+
+    /// Wait for the next heartbeat or claim that needs to be sent.
+    pub(crate) async fn next(&mut self) -> Message {
+        loop {
+            self.interval.tick().await;
+            if let Some(msg) = self.tick(Instant::now()) {
+                break msg;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(msg: &Message) -> Lock {
+        serde_json::from_str(&msg.payload_str()).unwrap()
+    }
+
+    fn alive(id: &str) -> String {
+        format!(r#"{{"id":"{id}","state":"alive"}}"#)
+    }
+
+    fn released(id: &str) -> String {
+        format!(r#"{{"id":"{id}","state":"released"}}"#)
+    }
+
+    #[tokio::test]
+    async fn test_claim_when_nobody_is_leader() {
+        let flag = Arc::new(AtomicBool::new(true));
+        let mut election = Election::new("lock", "a", Duration::from_secs(10), flag.clone());
+        let start = Instant::now();
+        assert!(!flag.load(Ordering::Relaxed));
+
+        assert!(election.tick(start + Duration::from_secs(10)).is_none());
+        let claim = election.tick(start + Duration::from_secs(30)).unwrap();
+        assert!(claim.retained());
+        assert_eq!(
+            lock(&claim),
+            Lock {
+                id: "a".to_string(),
+                state: LockState::Alive
+            }
+        );
+        assert!(!flag.load(Ordering::Relaxed));
+
+        assert!(election.handle(&alive("a"), start).is_none());
+        assert!(flag.load(Ordering::Relaxed));
+        assert!(election.tick(start).is_some());
+        assert_eq!(
+            lock(&election.release().unwrap()).state,
+            LockState::Released
+        );
+    }
+
+    #[tokio::test]
+    async fn test_standby_takes_over() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut election = Election::new("lock", "b", Duration::from_secs(10), flag.clone());
+        let start = Instant::now();
+
+        assert!(election.handle(&alive("a"), start).is_none());
+        assert!(election.tick(start + Duration::from_secs(20)).is_none());
+        assert!(election.release().is_none());
+
+        // Another standby going away does not release the lock.
+        assert!(election.handle(&released("c"), start).is_none());
+        assert!(!flag.load(Ordering::Relaxed));
+
+        let claim = election.handle(&released("a"), start).unwrap();
+        assert_eq!(lock(&claim).id, "b");
+        assert!(!flag.load(Ordering::Relaxed));
+        election.handle(&alive("b"), start);
+        assert!(flag.load(Ordering::Relaxed));
+
+        // The last claim wins.
+        election.handle(&alive("a"), start);
+        assert!(!flag.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_claim_when_heartbeat_stops() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut election = Election::new("lock", "b", Duration::from_secs(10), flag);
+        let start = Instant::now();
+
+        election.handle(&alive("a"), start);
+        assert!(election.tick(start + Duration::from_secs(29)).is_none());
+        let claim = election.tick(start + Duration::from_secs(30)).unwrap();
+        assert_eq!(lock(&claim).id, "b");
+    }
+
+    #[tokio::test]
+    async fn test_start_after_standby_went_away() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut election = Election::new("lock", "d", Duration::from_secs(10), flag);
+        let start = Instant::now();
+
+        // The will of standby c replaced the heartbeat of leader a, which is still alive.
+        assert!(election.handle(&released("c"), start).is_none());
+        assert!(election.handle(&alive("a"), start).is_none());
+        assert!(election.tick(start + Duration::from_secs(20)).is_none());
+
+        // If nobody sends a heartbeat, the lock is claimed once the leader times out.
+        let mut election = Election::new("lock", "d", Duration::from_secs(10), Arc::default());
+        let start = Instant::now();
+        assert!(election.handle(&released("c"), start).is_none());
+        let claim = election.tick(start + Duration::from_secs(30)).unwrap();
+        assert_eq!(lock(&claim).id, "d");
+    }
+}
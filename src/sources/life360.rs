@@ -6,15 +6,17 @@ use serde::Deserialize;
 use serde::Serialize;
 use std::cmp::min;
 use std::{env, time::Duration};
-use tokio::sync::broadcast;
 use tokio::time::MissedTickBehavior;
 
 use tokio::time;
 
+use crate::registry::register_node;
+use crate::runtime::wait_for_shutdown;
 use crate::send_or_log;
 use crate::spawn;
 use crate::Pipe;
 use crate::RxPipe;
+use crate::Sender;
 
 #[derive(Deserialize)]
 struct Login {
@@ -148,9 +150,22 @@ struct Circle {
 }
 
 /// Source of life360 member information.
+///
+/// Polling stops when shutdown is requested.
 pub fn circles() -> RxPipe<Member> {
+    circles_with_polls().0
+}
+
+/// Source of life360 member information, and of a trigger sent after every poll.
+///
+/// The trigger is sent once all members from the poll have been sent, so it can be used
+/// with [RxPipe::buffer_until] to collect the members of each poll.
+pub fn circles_with_polls() -> (RxPipe<Member>, RxPipe<()>) {
     let output = Pipe::new();
+    let polled = Pipe::new();
     let tx = output.get_tx();
+    let tx_polled = polled.get_tx();
+    register_node("life360", &[], &[output.id(), polled.id()]);
 
     spawn(async move {
         let username = env::var("LIFE360_USERNAME").expect("LIFE360_USERNAME should be set");
@@ -173,14 +188,16 @@ pub fn circles() -> RxPipe<Member> {
                         circles = get_circles_or_none(&login).await;
                     }
                     if let Some(circles) = &circles {
-                        dispatch_circle_details(&login, circles, &tx).await;
+                        dispatch_circle_details(&login, circles, &tx, &tx_polled).await;
                     }
                 }
+
+                _ = wait_for_shutdown() => { break; }
             }
         }
     });
 
-    output.to_rx_pipe()
+    (output.to_rx_pipe(), polled.to_rx_pipe())
 }
 
 async fn retry_login(username: &str, password: &str) -> Login {
@@ -232,17 +249,23 @@ async fn get_circles_or_none(login: &Login) -> Option<List> {
     }
 }
 
-async fn dispatch_circle_details(login: &Login, circles: &List, tx: &broadcast::Sender<Member>) {
+async fn dispatch_circle_details(
+    login: &Login,
+    circles: &List,
+    tx: &Sender<Member>,
+    tx_polled: &Sender<()>,
+) {
     for circle in &circles.circles {
         match get_circle_details(login, circle).await {
             Err(err) => error!("get_circle_details: {err}"),
             Ok(details) => {
                 for member in details.members {
-                    send_or_log(tx, member);
+                    send_or_log(tx, member).await;
                 }
             }
         }
     }
+    send_or_log(tx_polled, ()).await;
 }
 
 async fn login(username: &str, password: &str) -> Result<Login> {
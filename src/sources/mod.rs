@@ -1,4 +1,5 @@
 //! Sources of async data.
+pub mod election;
 pub mod life360;
 pub mod mqtt;
 pub mod timer;
@@ -7,21 +7,32 @@ use paho_mqtt::Message;
 use paho_mqtt::SslOptionsBuilder;
 use std::cmp::min;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{env, str};
 use tokio::select;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tokio::time::timeout;
 use tokio::time::Instant;
 
+use super::election::Election;
+
 use crate::recv;
+use crate::registry::register_named_node;
+use crate::registry::register_node;
+use crate::reload;
+use crate::runtime::wait_for_shutdown;
 use crate::send_or_log;
 use crate::spawn;
+use crate::trace;
+use crate::OverflowPolicy;
 use crate::Pipe;
+use crate::Receiver;
 use crate::RxPipe;
+use crate::Sender;
 use crate::TxPipe;
 
 #[derive(Debug, Clone)]
@@ -32,8 +43,10 @@ enum MqttMessage {
 /// Client struct used to connect to MQTT.
 pub struct MqttClient {
     b: Option<JoinHandle<()>>,
-    pipe: Pipe<MqttMessage>,
+    pipe: Option<Pipe<MqttMessage>>,
     rx: Option<Receiver<MqttMessage>>,
+    election: Option<(String, Duration)>,
+    is_leader: Arc<AtomicBool>,
 }
 
 /// Struct used to send outgoing MQTT messages.
@@ -42,21 +55,52 @@ pub struct MqttOut(TxPipe<MqttMessage>);
 impl MqttClient {
     /// Create a new MQTT client.
     pub async fn new() -> Self {
-        // Outgoing MQTT queue.
-        let pipe = Pipe::new_with_size(50);
+        // Outgoing MQTT queue, commands must not get lost if the broker is slow.
+        let pipe = Pipe::new_with_policy(50, OverflowPolicy::Backpressure);
+        register_node("mqtt_out", &[pipe.id()], &[]);
 
         // Subscribe now so we don't miss any out
         let rx = Some(pipe.to_rx_pipe().subscribe());
 
-        MqttClient { b: None, pipe, rx }
+        MqttClient {
+            b: None,
+            pipe: Some(pipe),
+            rx,
+            election: None,
+            is_leader: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Elect a leader using the retained `topic`, so more then one copy can run.
+    ///
+    /// Only the leader publishes outgoing messages, see [super::election]. The leader sends
+    /// a heartbeat every `heartbeat`. This must be called before [Self::connect].
+    pub fn enable_leader_election(&mut self, topic: &str, heartbeat: Duration) {
+        self.election = Some((topic.to_string(), heartbeat));
+    }
+
+    /// Are we the leader?
+    ///
+    /// Always true if leader election is not enabled.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::Relaxed)
     }
 
     /// Get the [MqttOut] struct for sending outgoing messages.
+    ///
+    /// This must be called before [Self::connect].
     pub fn get_mqtt_out(&mut self) -> MqttOut {
-        MqttOut(self.pipe.to_tx_pipe())
+        let pipe = self
+            .pipe
+            .as_ref()
+            .expect("get_mqtt_out called after connect");
+        MqttOut(pipe.to_tx_pipe())
     }
 
     /// Connect to the MQTT broker.
+    ///
+    /// When shutdown is requested, the subscriptions are closed and all outgoing messages
+    /// are published until every [MqttOut] has been dropped. Then the client disconnects.
     pub fn connect(&mut self, subscriptions: Subscriptions) {
         // Define the set of options for the create.
         // Use an ID for a persistent session.
@@ -70,6 +114,11 @@ impl MqttClient {
         let hostname = hostname.to_str().unwrap();
         let client_id = format!("robotica-node-rust-{hostname}");
 
+        let mut election = self.election.take().map(|(topic, heartbeat)| {
+            Election::new(&topic, &client_id, heartbeat, self.is_leader.clone())
+        });
+        let is_leader = self.is_leader.clone();
+
         let create_opts = CreateOptionsBuilder::new()
             .server_uri(&uri)
             .client_id(client_id)
@@ -84,6 +133,10 @@ impl MqttClient {
         let mqtt_in_rx = cli.get_stream(50);
 
         let rx = self.rx.take().unwrap();
+
+        // Drop our sender, so the outgoing pipe closes once every MqttOut is dropped.
+        self.pipe = None;
+
         let b = spawn(async move {
             let trust_store = env::var("MQTT_CA_CERT_FILE").unwrap();
 
@@ -93,13 +146,19 @@ impl MqttClient {
                 .finalize();
 
             // Define the set of options for the connection.
-            let conn_opts = ConnectOptionsBuilder::new()
-                .ssl_options(ssl_opts)
-                .keep_alive_interval(Duration::from_secs(30))
-                .clean_session(true)
-                .user_name(env::var("MQTT_USERNAME").expect("MQTT_USERNAME should be set"))
-                .password(env::var("MQTT_PASSWORD").expect("MQTT_PASSWORD should be set"))
-                .finalize();
+            let conn_opts = {
+                let mut builder = ConnectOptionsBuilder::new();
+                builder
+                    .ssl_options(ssl_opts)
+                    .keep_alive_interval(Duration::from_secs(30))
+                    .clean_session(true)
+                    .user_name(env::var("MQTT_USERNAME").expect("MQTT_USERNAME should be set"))
+                    .password(env::var("MQTT_PASSWORD").expect("MQTT_PASSWORD should be set"));
+                if let Some(election) = &election {
+                    builder.will_message(election.last_will());
+                }
+                builder.finalize()
+            };
 
             // Connect and wait for it to complete or fail.
             if let Err(e) = cli.connect(conn_opts).await {
@@ -109,7 +168,7 @@ impl MqttClient {
             let mut rx = rx;
 
             // Subscribe topics.
-            subscribe_topics(&cli, &subscriptions).await;
+            subscribe_topics(&cli, &subscriptions, &election).await;
 
             loop {
                 select! {
@@ -119,41 +178,54 @@ impl MqttClient {
                             let payload = msg.payload();
                             let payload = str::from_utf8(payload).unwrap().to_string();
                             debug!("incoming mqtt {topic} {payload}");
-                            if let Some(subscription) = subscriptions.get(topic) {
-                                send_or_log(&subscription.tx, msg);
+                            if let Some(election) = election.as_mut().filter(|e| e.topic() == topic) {
+                                if let Some(msg) = election.handle(&payload, Instant::now()) {
+                                    publish_lock(&cli, msg).await;
+                                }
+                            } else if let Some(tx) = subscriptions.get(topic) {
+                                trace::start(topic);
+                                send_or_log(&tx, msg).await;
                             }
                         } else if !cli.is_connected() {
                             try_reconnect(&cli).await;
                             debug!("Resubscribe topics...");
-                            subscribe_topics(&cli, &subscriptions).await;
+                            subscribe_topics(&cli, &subscriptions, &election).await;
                         }
                     },
                     Ok(msg) = recv(&mut rx) => {
-                        let now = Instant::now();
-                        match msg {
-                            MqttMessage::MqttOut(_, instant) if message_expired(&now, &instant) => {
-                                warn!("Discarding outgoing message as too old");
-                            },
-                            MqttMessage::MqttOut(msg, _) => {
-                                let debug_mode: bool = is_debug_mode();
-
-                                info!(
-                                    "outgoing mqtt {} {} {} {}",
-                                    if debug_mode { "nop" } else { "live" },
-                                    msg.retained(),
-                                    msg.topic(),
-                                    str::from_utf8(msg.payload()).unwrap().to_string()
-                                );
-
-                                if !debug_mode {
-                                    cli.publish(msg).await.unwrap()
-                                }
-                            },
+                        publish(&cli, msg, &is_leader).await;
+                    }
+                    msg = next_heartbeat(&mut election) => {
+                        publish_lock(&cli, msg).await;
+                    }
+                    topics = subscriptions.added() => {
+                        debug!("Subscribe new topics {topics:?}");
+                        let qos: Vec<_> = topics.iter().map(|_| 0).collect();
+                        if let Err(e) = cli.subscribe_many(&topics, &qos).await {
+                            error!("Error subscribing to topics: {:?}", e);
                         }
                     }
+                    _ = wait_for_shutdown() => { break; }
                     else => { break; }
                 };
             }
+
+            // Close all subscriptions, and publish whatever the flows still send us.
+            info!("Flushing outgoing mqtt messages");
+            subscriptions.close();
+            while let Ok(msg) = recv(&mut rx).await {
+                publish(&cli, msg, &is_leader).await;
+            }
+
+            if let Some(msg) = election.as_ref().and_then(Election::release) {
+                info!("Releasing the leader lock");
+                publish_lock(&cli, msg).await;
+            }
+
+            info!("Disconnecting from mqtt");
+            if let Err(e) = cli.disconnect(None).await {
+                error!("Error disconnecting from mqtt: {:?}", e);
+            }
         });
 
         self.b = Some(b);
@@ -167,6 +239,53 @@ impl MqttClient {
     }
 }
 
+async fn publish(cli: &AsyncClient, msg: MqttMessage, is_leader: &AtomicBool) {
+    let now = Instant::now();
+    match msg {
+        MqttMessage::MqttOut(_, instant) if message_expired(&now, &instant) => {
+            warn!("Discarding outgoing message as too old");
+        }
+        MqttMessage::MqttOut(msg, _) => {
+            let debug_mode: bool = is_debug_mode();
+            let standby = !is_leader.load(Ordering::Relaxed);
+            let payload = str::from_utf8(msg.payload()).unwrap();
+
+            info!(
+                "outgoing mqtt {} {} {} {}",
+                if debug_mode {
+                    "nop"
+                } else if standby {
+                    "standby"
+                } else {
+                    "live"
+                },
+                msg.retained(),
+                msg.topic(),
+                payload
+            );
+            trace::record_published(msg.topic(), payload);
+
+            if !debug_mode && !standby {
+                cli.publish(msg).await.unwrap()
+            }
+        }
+    }
+}
+
+async fn publish_lock(cli: &AsyncClient, msg: Message) {
+    debug!("outgoing mqtt lock {} {}", msg.topic(), msg.payload_str());
+    if let Err(e) = cli.publish(msg).await {
+        error!("Error publishing the leader lock: {:?}", e);
+    }
+}
+
+async fn next_heartbeat(election: &mut Option<Election>) -> Message {
+    match election {
+        Some(election) => election.next().await,
+        None => std::future::pending().await,
+    }
+}
+
 fn message_expired(now: &Instant, sent: &Instant) -> bool {
     (*now - *sent) > Duration::from_secs(300)
 }
@@ -175,40 +294,87 @@ fn message_expired(now: &Instant, sent: &Instant) -> bool {
 struct Subscription {
     #[allow(dead_code)]
     topic: String,
-    tx: broadcast::Sender<Message>,
+    tx: Sender<Message>,
 }
 
 fn message_to_string(msg: Message) -> String {
     str::from_utf8(msg.payload()).unwrap().to_string()
 }
 
+#[derive(Default)]
+struct SubscriptionsInner {
+    topics: HashMap<String, Subscription>,
+    /// Topics the client has not subscribed to yet.
+    pending: Vec<String>,
+}
+
 /// List of all required subscriptions.
-pub struct Subscriptions(HashMap<String, Subscription>);
+///
+/// Clones share the same subscriptions, so flows can still subscribe after the list was
+/// given to [MqttClient::connect], for example when they are [crate::reload]ed. Topics are
+/// never unsubscribed.
+#[derive(Clone)]
+pub struct Subscriptions(Arc<Mutex<SubscriptionsInner>>, Arc<Notify>);
 
 impl Subscriptions {
     /// Create a new set of subscriptions.
     pub fn new() -> Self {
-        Subscriptions(HashMap::new())
+        Subscriptions(Arc::default(), Arc::default())
     }
 
-    fn get(&self, topic: &str) -> Option<&Subscription> {
-        self.0.get(topic)
+    fn get(&self, topic: &str) -> Option<Sender<Message>> {
+        let inner = self.0.lock().unwrap();
+        inner.topics.get(topic).map(|s| s.tx.clone())
+    }
+
+    fn topics(&self) -> Vec<String> {
+        let mut inner = self.0.lock().unwrap();
+        inner.pending.clear();
+        inner.topics.keys().cloned().collect()
+    }
+
+    /// Wait for new subscriptions, and return their topics.
+    async fn added(&self) -> Vec<String> {
+        loop {
+            self.1.notified().await;
+            let pending = std::mem::take(&mut self.0.lock().unwrap().pending);
+            if !pending.is_empty() {
+                break pending;
+            }
+        }
+    }
+
+    /// Close every subscription, so the flows reading them can finish.
+    fn close(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.topics.clear();
+        inner.pending.clear();
     }
 
     /// Add a new subscription.
+    ///
+    /// If the topic is already subscribed, the existing pipe is reused.
     pub fn subscribe(&mut self, topic: &str) -> RxPipe<Message> {
+        let mut inner = self.0.lock().unwrap();
+
         // Per subscription incoming MQTT queue.
-        if let Some(subscription) = self.0.get(topic) {
+        if let Some(subscription) = inner.topics.get(topic) {
             RxPipe::new_from_sender(subscription.tx.clone())
         } else {
             let output = Pipe::new();
+            // The subscription outlives any reloadable flows that asked for it.
+            reload::outside_group(|| {
+                register_named_node("subscribe", topic, &[], &[output.id()]);
+            });
 
             let subscription = Subscription {
                 topic: topic.to_string(),
                 tx: output.get_tx(),
             };
 
-            self.0.insert(topic.to_string(), subscription);
+            inner.topics.insert(topic.to_string(), subscription);
+            inner.pending.push(topic.to_string());
+            self.1.notify_one();
             output.to_rx_pipe()
         }
     }
@@ -251,13 +417,17 @@ async fn try_reconnect(cli: &AsyncClient) {
     }
 }
 
-async fn subscribe_topics(cli: &AsyncClient, subscriptions: &Subscriptions) {
-    let topics: Vec<_> = subscriptions
-        .0
-        .iter()
-        .map(|(topic, _)| topic.clone())
-        .collect();
-    let qos: Vec<_> = subscriptions.0.iter().map(|_| 0).collect();
+async fn subscribe_topics(
+    cli: &AsyncClient,
+    subscriptions: &Subscriptions,
+    election: &Option<Election>,
+) {
+    let mut topics = subscriptions.topics();
+    let mut qos: Vec<_> = topics.iter().map(|_| 0).collect();
+    if let Some(election) = election {
+        topics.push(election.topic().to_string());
+        qos.push(1);
+    }
 
     if let Err(e) = cli.subscribe_many(&topics, &qos).await {
         error!("Error subscribing to topics: {:?}", e);
@@ -273,11 +443,20 @@ fn is_debug_mode() -> bool {
 
 impl RxPipe<Message> {
     /// Publish an outgoing message.
+    ///
+    /// Messages are never dropped on the way to the MQTT client, if the client cannot keep up
+    /// this waits for it. Start the flow with [RxPipe::with_policy] and
+    /// [OverflowPolicy::Backpressure] to make sure nothing is dropped before it gets here either.
     pub fn publish(&mut self, mqtt_out: &MqttOut) {
-        self.map(|msg| {
-            let now = Instant::now();
-            MqttMessage::MqttOut(msg, now)
-        })
-        .copy_to(&mqtt_out.0);
+        let output = mqtt_out.0.get_tx();
+        register_node("publish", &[self.id()], &[output.id()]);
+
+        let mut input = self.subscribe();
+        spawn(async move {
+            while let Ok(msg) = recv(&mut input).await {
+                let now = Instant::now();
+                send_or_log(&output, MqttMessage::MqttOut(msg, now)).await;
+            }
+        });
     }
 }
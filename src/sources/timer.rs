@@ -1,21 +1,29 @@
 //! Sources that use timers to produce async data.
 use std::time::Duration;
 
-use tokio::time;
+use tokio::{select, time};
 
+use crate::registry::register_node;
+use crate::runtime::wait_for_shutdown;
 use crate::{send_or_log, spawn, Pipe, RxPipe};
 
 /// Create a timer that sends outgoing messages at regularly spaced intervals.
+///
+/// The timer stops when shutdown is requested.
 pub fn timer<T: Clone + Send + 'static>(duration: Duration, value: T) -> RxPipe<T> {
     let output = Pipe::new();
     let tx = output.get_tx();
+    register_node("timer", &[], &[output.id()]);
 
     spawn(async move {
         let mut interval = time::interval(duration);
 
         loop {
-            send_or_log(&tx, value.clone());
-            interval.tick().await;
+            send_or_log(&tx, value.clone()).await;
+            select! {
+                _ = interval.tick() => {},
+                _ = wait_for_shutdown() => { break; }
+            }
         }
     });
 
@@ -29,6 +37,7 @@ mod tests {
     use super::*;
 
     #[tokio::test]
+    #[allow(clippy::redundant_pattern_matching)]
     async fn test_timer() {
         let duration = Duration::from_millis(100);
         let wait_duration = Duration::from_millis(200);
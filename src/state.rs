@@ -0,0 +1,350 @@
+//! State pipes that only keep the latest value.
+//!
+//! Unlike [RxPipe], subscribers see the current value as soon as they subscribe, and if
+//! values change faster then they can be processed only the latest value is seen.
+use std::sync::Arc;
+use tokio::select;
+use tokio::sync::{watch, Mutex};
+
+use crate::registry::{self, register_node, PipeId};
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::{recv, send_or_log, spawn, Pipe, RxPipe};
+
+/// A state pipe that can be used to set the current value.
+pub struct StatePipe<T>(watch::Sender<Option<T>>, PipeId);
+
+impl<T: Send + Sync + Clone + 'static> StatePipe<T> {
+    #[allow(clippy::new_without_default)]
+    /// Create a new state pipe with no value.
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(None);
+        Self(tx, PipeId::new())
+    }
+
+    /// Create a new state pipe with an initial value.
+    pub fn new_with_value(value: T) -> Self {
+        let (tx, _) = watch::channel(Some(value));
+        Self(tx, PipeId::new())
+    }
+
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.1
+    }
+
+    /// Set the current value.
+    pub fn set(&self, value: T) {
+        self.0.send_replace(Some(value));
+    }
+
+    /// Convert the pipe to an [RxState].
+    pub fn to_rx_state(&self) -> RxState<T> {
+        RxState(self.0.subscribe(), self.1)
+    }
+}
+
+/// A state pipe that can only be used to receive the current value.
+///
+/// This does not keep the pipe open, once the sender is dropped the pipe is closed.
+pub struct RxState<T>(watch::Receiver<Option<T>>, PipeId);
+
+impl<T> Clone for RxState<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1)
+    }
+}
+
+/// Receive every known value from a state pipe, starting with the current value.
+struct StateInput<T> {
+    rx: watch::Receiver<Option<T>>,
+    started: bool,
+}
+
+impl<T: Clone> StateInput<T> {
+    fn new(rx: watch::Receiver<Option<T>>) -> Self {
+        Self { rx, started: false }
+    }
+
+    /// Wait for the next known value, returns None if the pipe was closed.
+    async fn next(&mut self) -> Option<T> {
+        loop {
+            if self.started && self.rx.changed().await.is_err() {
+                return None;
+            }
+            self.started = true;
+            if let Some(v) = self.rx.borrow_and_update().clone() {
+                return Some(v);
+            }
+        }
+    }
+}
+
+fn new_output<U>() -> (watch::Sender<Option<U>>, RxState<U>) {
+    let (tx, rx) = watch::channel(None);
+    (tx, RxState(rx, PipeId::new()))
+}
+
+impl<T: Send + Sync + Clone + 'static> RxState<T> {
+    /// Get the id of the pipe.
+    pub fn id(&self) -> PipeId {
+        self.1
+    }
+
+    /// Get the current value, if known.
+    pub fn get(&self) -> Option<T> {
+        self.0.borrow().clone()
+    }
+
+    /// Get the underlying receiver for the pipe.
+    pub fn subscribe(&self) -> watch::Receiver<Option<T>> {
+        self.0.clone()
+    }
+
+    /// Name the node that writes to this pipe.
+    ///
+    /// The name is used when exporting the flow graph from the [registry].
+    pub fn named(self, name: &str) -> Self {
+        registry::set_producer_name(self.1, name);
+        self
+    }
+
+    /// Map the current value through function and (optionally) change its type.
+    ///
+    /// If the function panics the task is restarted and processing continues with the next value.
+    pub fn map<U: Send + Sync + Clone + 'static>(
+        &self,
+        callback: impl Send + Sync + 'static + Fn(T) -> U,
+    ) -> RxState<U> {
+        let (tx, output) = new_output();
+        register_node("state_map", &[self.id()], &[output.id()]);
+
+        let input = Arc::new(Mutex::new(StateInput::new(self.subscribe())));
+        let tx = Arc::new(tx);
+        let callback = Arc::new(callback);
+        supervise("state_map", RestartPolicy::default(), move || {
+            let input = input.clone();
+            let tx = tx.clone();
+            let callback = callback.clone();
+            async move {
+                let mut input = input.lock().await;
+                while let Some(v) = input.next().await {
+                    tx.send_replace(Some(callback(v)));
+                }
+            }
+        });
+
+        output
+    }
+
+    /// Only update the value if the function returns true.
+    ///
+    /// Otherwise the previous value is kept.
+    pub fn filter(&self, callback: impl Send + Sync + 'static + Fn(&T) -> bool) -> RxState<T> {
+        let (tx, output) = new_output();
+        register_node("state_filter", &[self.id()], &[output.id()]);
+
+        let input = Arc::new(Mutex::new(StateInput::new(self.subscribe())));
+        let tx = Arc::new(tx);
+        let callback = Arc::new(callback);
+        supervise("state_filter", RestartPolicy::default(), move || {
+            let input = input.clone();
+            let tx = tx.clone();
+            let callback = callback.clone();
+            async move {
+                let mut input = input.lock().await;
+                while let Some(v) = input.next().await {
+                    if callback(&v) {
+                        tx.send_replace(Some(v));
+                    }
+                }
+            }
+        });
+
+        output
+    }
+
+    /// Add the previous value to the current value.
+    ///
+    /// If there was no previous value, then add None.
+    pub fn diff(&self) -> RxState<(Option<T>, T)> {
+        let (tx, output) = new_output();
+        register_node("state_diff", &[self.id()], &[output.id()]);
+
+        let mut input = StateInput::new(self.subscribe());
+        spawn(async move {
+            let mut old_value = None;
+            while let Some(v) = input.next().await {
+                tx.send_replace(Some((old_value, v.clone())));
+                old_value = Some(v);
+            }
+        });
+
+        output
+    }
+
+    /// Only follow the value while `allow` is true.
+    ///
+    /// While `allow` is false or unknown, the previous value is kept. When `allow` becomes
+    /// true, the output is updated to the current value.
+    pub fn gate(&self, allow: RxState<bool>) -> RxState<T> {
+        let (tx, output) = new_output();
+        register_node("state_gate", &[self.id(), allow.id()], &[output.id()]);
+
+        let mut input = StateInput::new(self.subscribe());
+        let mut allow = StateInput::new(allow.subscribe());
+        spawn(async move {
+            let mut the_value: Option<T> = None;
+            let mut the_allow = false;
+
+            loop {
+                select! {
+                    Some(v) = input.next() => { the_value = Some(v) },
+                    Some(v) = allow.next() => { the_allow = v },
+                    else => { break; }
+                }
+
+                if the_allow && the_value.is_some() {
+                    tx.send_replace(the_value.clone());
+                }
+            }
+        });
+
+        output
+    }
+
+    /// Send every new value to an [RxPipe].
+    pub fn to_rx_pipe(&self) -> RxPipe<T> {
+        let output = Pipe::new();
+        register_node("state_to_rx_pipe", &[self.id()], &[output.id()]);
+
+        let mut input = StateInput::new(self.subscribe());
+        let tx = output.get_tx();
+        spawn(async move {
+            while let Some(v) = input.next().await {
+                send_or_log(&tx, v).await;
+            }
+        });
+
+        output.to_rx_pipe()
+    }
+}
+
+impl<T: Send + Sync + Clone + 'static> RxPipe<T> {
+    /// Keep only the latest value received from this pipe.
+    pub fn to_rx_state(&self) -> RxState<T> {
+        let (tx, output) = new_output();
+        register_node("to_rx_state", &[self.id()], &[output.id()]);
+
+        let mut input = self.subscribe();
+        spawn(async move {
+            while let Ok(v) = recv(&mut input).await {
+                tx.send_replace(Some(v));
+            }
+        });
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait_for<T: Clone>(rx: &mut watch::Receiver<Option<T>>, f: impl Fn(&T) -> bool) -> T {
+        let v = rx
+            .wait_for(|v| v.as_ref().map(&f).unwrap_or(false))
+            .await
+            .unwrap();
+        v.clone().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_late_subscriber_sees_current_value() {
+        let pipe = StatePipe::new();
+        let rx = pipe.to_rx_state();
+        assert_eq!(rx.get(), None);
+
+        pipe.set(10);
+        pipe.set(20);
+        let late = rx.clone();
+        assert_eq!(late.get(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        let pipe = StatePipe::new_with_value(10);
+        let mut rx = pipe.to_rx_state().map(|v| v + 1).subscribe();
+
+        let v = wait_for(&mut rx, |_| true).await;
+        assert_eq!(v, 11);
+
+        pipe.set(20);
+        let v = wait_for(&mut rx, |v| *v != 11).await;
+        assert_eq!(v, 21);
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let pipe = StatePipe::new_with_value(10);
+        let output = pipe.to_rx_state().filter(|v| *v > 10);
+        let mut rx = output.subscribe();
+
+        pipe.set(20);
+        let v = wait_for(&mut rx, |_| true).await;
+        assert_eq!(v, 20);
+
+        pipe.set(5);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(output.get(), Some(20));
+
+        pipe.set(30);
+        let v = wait_for(&mut rx, |v| *v != 20).await;
+        assert_eq!(v, 30);
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        let pipe = StatePipe::new_with_value(10);
+        let mut rx = pipe.to_rx_state().diff().subscribe();
+
+        let v = wait_for(&mut rx, |_| true).await;
+        assert_eq!(v, (None, 10));
+
+        pipe.set(20);
+        let v = wait_for(&mut rx, |(_, new)| *new == 20).await;
+        assert_eq!(v, (Some(10), 20));
+    }
+
+    #[tokio::test]
+    async fn test_gate() {
+        let pipe = StatePipe::new_with_value(10);
+        let allow = StatePipe::new_with_value(false);
+        let mut rx = pipe.to_rx_state().gate(allow.to_rx_state()).subscribe();
+
+        pipe.set(20);
+        allow.set(true);
+        let v = wait_for(&mut rx, |_| true).await;
+        assert_eq!(v, 20);
+
+        allow.set(false);
+        pipe.set(30);
+        allow.set(true);
+        let v = wait_for(&mut rx, |v| *v != 20).await;
+        assert_eq!(v, 30);
+    }
+
+    #[tokio::test]
+    async fn test_to_rx_pipe_and_back() {
+        let pipe = Pipe::new();
+        let state = pipe.to_rx_pipe().to_rx_state();
+        let mut events = state.to_rx_pipe().subscribe();
+        let mut rx = state.subscribe();
+
+        pipe.get_tx().send(10).await.unwrap();
+        let v = wait_for(&mut rx, |_| true).await;
+        assert_eq!(v, 10);
+
+        let v = events.recv().await.unwrap();
+        assert_eq!(v, 10);
+    }
+}
@@ -0,0 +1,122 @@
+//! Use pipes with [futures] streams and sinks.
+use futures::sink::{self, Sink};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use std::convert::Infallible;
+use std::pin::Pin;
+use tokio::select;
+
+use crate::registry::register_node;
+use crate::runtime::wait_for_shutdown;
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::{recv, send_or_log, Pipe, Receiver, RxPipe, TxPipe};
+
+impl<T: Send + Clone + 'static> Receiver<T> {
+    /// Convert the receiver into a [Stream] of values.
+    ///
+    /// Lagged values are logged and skipped, the stream ends when the pipe is closed.
+    pub fn into_stream(self) -> BoxStream<'static, T> {
+        stream::unfold(self, |mut rx| async move {
+            let v = recv(&mut rx).await.ok()?;
+            Some((v, rx))
+        })
+        .boxed()
+    }
+}
+
+impl<T: Send + Clone + 'static> RxPipe<T> {
+    /// Create a pipe from the [Stream] returned by `factory`.
+    ///
+    /// The pipe is closed when the stream ends, or shutdown is requested. A stream that
+    /// panicked cannot be polled again, so if polling panics the task is restarted with a new
+    /// stream from `factory`.
+    pub fn from_stream<S>(mut factory: impl FnMut() -> S + Send + 'static) -> RxPipe<T>
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let output = Pipe::new();
+        register_node("from_stream", &[], &[output.id()]);
+
+        let tx = output.get_tx();
+        supervise("from_stream", RestartPolicy::default(), move || {
+            let mut stream = factory().boxed();
+            let tx = tx.clone();
+            async move {
+                loop {
+                    select! {
+                        v = stream.next() => {
+                            let Some(v) = v else { break; };
+                            send_or_log(&tx, v).await;
+                        }
+                        _ = wait_for_shutdown() => { break; }
+                    }
+                }
+            }
+        });
+
+        output.to_rx_pipe()
+    }
+
+    /// Get a [Stream] of every value sent to this pipe from now on.
+    pub fn to_stream(&self) -> BoxStream<'static, T> {
+        self.subscribe().into_stream()
+    }
+}
+
+impl<T: Send + Clone + 'static> TxPipe<T> {
+    /// Get a [Sink] that sends every value to this pipe.
+    ///
+    /// Like the other operators, errors sending to the pipe are logged and never returned.
+    pub fn to_sink(&self) -> Pin<Box<dyn Sink<T, Error = Infallible> + Send>> {
+        let tx = self.get_tx();
+        Box::pin(sink::unfold(tx, |tx, v| async move {
+            send_or_log(&tx, v).await;
+            Ok(tx)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+
+    #[tokio::test]
+    async fn test_from_stream() {
+        let pipe = RxPipe::from_stream(|| stream::iter(vec![1, 2, 3]));
+
+        let values: Vec<_> = pipe.to_stream().collect().await;
+        assert_eq!(values, vec![1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_from_stream_restarts_after_panic() {
+        let mut calls = 0;
+        let pipe = RxPipe::from_stream(move || {
+            calls += 1;
+            let first = calls == 1;
+            stream::iter(vec![1, 2]).map(move |v| {
+                assert!(!first, "bad stream");
+                v
+            })
+        });
+
+        let values: Vec<_> = pipe.to_stream().collect().await;
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_to_sink() {
+        let pipe = Pipe::new();
+        let mut values = pipe.to_rx_pipe().to_stream();
+        let mut sink = pipe.to_tx_pipe().to_sink();
+        drop(pipe);
+
+        sink.send(10).await.unwrap();
+        sink.send(20).await.unwrap();
+        drop(sink);
+
+        assert_eq!(values.next().await, Some(10));
+        assert_eq!(values.next().await, Some(20));
+        assert_eq!(values.next().await, None);
+    }
+}
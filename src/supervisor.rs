@@ -0,0 +1,193 @@
+//! Supervise tasks and restart them if they fail.
+use log::*;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{sleep, Instant};
+
+use crate::reload::StopHandle;
+use crate::runtime::{self, TaskGuard};
+use crate::trace;
+
+/// Policy that determines how a supervised task gets restarted.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Delay before the first restart.
+    pub initial_backoff: Duration,
+    /// Maximum delay between restarts, the delay doubles after every restart.
+    pub max_backoff: Duration,
+    /// Maximum number of restarts allowed within [Self::period] before the process exits.
+    pub max_restarts: usize,
+    /// Period used to calculate the restart rate.
+    pub period: Duration,
+}
+
+impl RestartPolicy {
+    /// Never restart the task, exit the process instead.
+    pub fn never() -> Self {
+        Self {
+            max_restarts: 0,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, restarts: usize) -> Duration {
+        let factor = 2u32.saturating_pow(restarts.try_into().unwrap_or(u32::MAX));
+        min_duration(
+            self.initial_backoff.saturating_mul(factor),
+            self.max_backoff,
+        )
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 5,
+            period: Duration::from_secs(60),
+        }
+    }
+}
+
+fn min_duration(a: Duration, b: Duration) -> Duration {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Describe why a task stopped.
+pub(crate) fn exit_reason(rc: Result<(), JoinError>) -> String {
+    match rc {
+        Ok(()) => "terminated".to_string(),
+        Err(err) if err.is_panic() => format!("panicked: {}", panic_message(err.into_panic())),
+        Err(err) => format!("aborted: {err}"),
+    }
+}
+
+/// Spawn a task and restart it whenever it panics.
+///
+/// A new task is created by calling `factory` on every restart. If the task needs to be
+/// restarted more then [RestartPolicy::max_restarts] times within [RestartPolicy::period]
+/// the failure is escalated and the process exits.
+///
+/// If the task finishes without panicking, for example because its input pipe was closed,
+/// it is not restarted.
+pub fn supervise<F, Fut>(name: &str, policy: RestartPolicy, mut factory: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name = name.to_string();
+    let guard = TaskGuard::new();
+    let stop = StopHandle::current();
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+
+        loop {
+            let rc = tokio::spawn(trace::scope(stop.clone().run(factory())))
+                .await
+                .map(|_| ());
+            if rc.is_ok() || runtime::is_shutting_down() {
+                debug!("The task {name} {}", exit_reason(rc));
+                break;
+            }
+            let reason = exit_reason(rc);
+
+            let now = Instant::now();
+            while let Some(instant) = restarts.front() {
+                if now.duration_since(*instant) > policy.period {
+                    restarts.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            if restarts.len() >= policy.max_restarts {
+                error!(
+                    "The task {name} {reason}, giving up after {} restarts",
+                    restarts.len()
+                );
+                std::process::exit(1);
+            }
+
+            let backoff = policy.backoff(restarts.len());
+            error!(
+                "The task {name} {reason}, restarting in {} ms",
+                backoff.as_millis()
+            );
+            restarts.push_back(now);
+            sleep(backoff).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            max_restarts: 10,
+            period: Duration::from_secs(60),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1), Duration::from_secs(2));
+        assert_eq!(policy.backoff(2), Duration::from_secs(4));
+        assert_eq!(policy.backoff(3), Duration::from_secs(5));
+        assert_eq!(policy.backoff(100), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_supervise_restarts() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            max_restarts: 5,
+            period: Duration::from_secs(60),
+        };
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (tx, mut rx) = mpsc::channel(10);
+
+        supervise("test", policy, move || {
+            let attempts = attempts.clone();
+            let tx = tx.clone();
+            async move {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                if attempt < 2 {
+                    panic!("attempt {attempt} failed");
+                }
+                tx.send(attempt).await.unwrap();
+                std::future::pending::<()>().await;
+            }
+        });
+
+        let v = rx.recv().await.unwrap();
+        assert_eq!(v, 2);
+    }
+}
@@ -0,0 +1,184 @@
+//! Helpers for testing flows deterministically using virtual time.
+//!
+//! These rely on tokio's paused clock, so tests should use
+//! `#[tokio::test(start_paused = true)]`. While time is paused, tokio only moves the clock
+//! forward once every task is idle, so all values sent at one virtual time are processed
+//! before anything scheduled for a later time.
+//!
+//! ```ignore
+//! #[tokio::test(start_paused = true)]
+//! async fn test_delay_true() {
+//!     let clock = TestClock::new();
+//!     let input = Pipe::new();
+//!     let injector = Injector::new(&clock, input.get_tx());
+//!     let output = PipeRecorder::new(&clock, input.to_rx_pipe().delay_true(ms(100)).subscribe());
+//!
+//!     injector.script([(ms(0), true)]);
+//!     output.assert_recorded_by(ms(150), &[(ms(100), true)]).await;
+//! }
+//! ```
+//!
+//! Downstream crates can enable these helpers with the `testing` feature.
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{recv, send_or_log, Receiver, Sender};
+
+/// How far past the requested time the clock is moved, to make sure it has been processed.
+const TICK: Duration = Duration::from_millis(1);
+
+/// Shorthand for [Duration::from_millis].
+pub fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// Measures virtual time since the start of a test.
+#[derive(Debug, Clone, Copy)]
+pub struct TestClock {
+    start: Instant,
+}
+
+impl TestClock {
+    #[allow(clippy::new_without_default)]
+    /// Start a new clock at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+
+    /// Virtual time elapsed since the clock was started.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - self.start
+    }
+
+    /// Wait until virtual time `t`.
+    ///
+    /// Values sent at exactly `t` might not have been processed yet when this returns.
+    pub async fn advance_to(&self, t: Duration) {
+        sleep_until(self.start + t).await;
+    }
+
+    /// Wait until everything scheduled up to and including virtual time `t` has been processed.
+    pub async fn settle_at(&self, t: Duration) {
+        self.advance_to(t + TICK).await;
+    }
+}
+
+/// Sends scripted values into a pipe at given virtual times.
+///
+/// The pipe is kept open until the injector is dropped.
+pub struct Injector<T> {
+    clock: TestClock,
+    tx: Sender<T>,
+}
+
+impl<T: Send + Clone + 'static> Injector<T> {
+    /// Create an injector that sends to `tx`.
+    pub fn new(clock: &TestClock, tx: Sender<T>) -> Self {
+        Self { clock: *clock, tx }
+    }
+
+    /// Send each value at its virtual time, in the order given.
+    ///
+    /// This returns immediately, the values are sent by a background task.
+    pub fn script(&self, events: impl IntoIterator<Item = (Duration, T)>) -> JoinHandle<()> {
+        let clock = self.clock;
+        let tx = self.tx.clone();
+        let events: Vec<_> = events.into_iter().collect();
+
+        tokio::spawn(async move {
+            for (t, value) in events {
+                clock.advance_to(t).await;
+                send_or_log(&tx, value).await;
+            }
+        })
+    }
+}
+
+/// Records every value received from a pipe along with its virtual timestamp.
+pub struct PipeRecorder<T> {
+    clock: TestClock,
+    values: Arc<Mutex<Vec<(Duration, T)>>>,
+}
+
+impl<T: Send + Clone + 'static> PipeRecorder<T> {
+    /// Start recording the values received by `rx`.
+    pub fn new(clock: &TestClock, mut rx: Receiver<T>) -> Self {
+        let clock = *clock;
+        let values = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = values.clone();
+        tokio::spawn(async move {
+            while let Ok(v) = recv(&mut rx).await {
+                recorded.lock().unwrap().push((clock.elapsed(), v));
+            }
+        });
+
+        Self { clock, values }
+    }
+
+    /// Every value recorded so far, with its virtual timestamp.
+    pub fn recorded(&self) -> Vec<(Duration, T)> {
+        self.values.lock().unwrap().clone()
+    }
+
+    /// Every value recorded so far.
+    pub fn values(&self) -> Vec<T> {
+        self.recorded().into_iter().map(|(_, v)| v).collect()
+    }
+
+    /// Wait until virtual time `t`, then get every value recorded up to and including `t`.
+    pub async fn recorded_by(&self, t: Duration) -> Vec<(Duration, T)> {
+        self.clock.settle_at(t).await;
+        self.recorded()
+            .into_iter()
+            .filter(|(at, _)| *at <= t)
+            .collect()
+    }
+
+    /// Wait until virtual time `t`, then get every value received up to and including `t`.
+    pub async fn values_by(&self, t: Duration) -> Vec<T> {
+        let recorded = self.recorded_by(t).await;
+        recorded.into_iter().map(|(_, v)| v).collect()
+    }
+}
+
+impl<T: Send + Clone + Debug + PartialEq + 'static> PipeRecorder<T> {
+    /// Assert exactly these values were received by virtual time `t`.
+    pub async fn assert_values_by(&self, t: Duration, expected: &[T]) {
+        let values = self.values_by(t).await;
+        assert_eq!(values, expected, "values received by {t:?}");
+    }
+
+    /// Assert exactly these values were received at these virtual times, by virtual time `t`.
+    pub async fn assert_recorded_by(&self, t: Duration, expected: &[(Duration, T)]) {
+        let recorded = self.recorded_by(t).await;
+        assert_eq!(recorded, expected, "values received by {t:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recorder_timestamps() {
+        let clock = TestClock::new();
+        let (tx, rx) = channel(10);
+        let injector = Injector::new(&clock, tx);
+        let recorder = PipeRecorder::new(&clock, rx);
+
+        injector.script([(ms(0), 1), (ms(100), 2), (ms(250), 3)]);
+
+        recorder
+            .assert_recorded_by(ms(100), &[(ms(0), 1), (ms(100), 2)])
+            .await;
+        recorder.assert_values_by(ms(1000), &[1, 2, 3]).await;
+        assert!(clock.elapsed() > ms(1000));
+    }
+}
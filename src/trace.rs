@@ -0,0 +1,282 @@
+//! Trace messages back to the MQTT messages that caused them.
+//!
+//! Tracing is opt-in, call [enable] at startup. Every message received from MQTT then starts
+//! a new [Trace], which records the topic and when it was received. Messages carry their
+//! trace through every pipe, and every operator that sends a message passes on the trace of
+//! the last message it received. Each hop is a [tracing] span, and the last messages
+//! published to MQTT are remembered, so [why] can explain where they came from.
+//!
+//! Operators that combine several inputs only pass on the trace of the most recent input.
+//! State pipes do not carry traces.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::registry::{producer_label, PipeId};
+
+/// How many published messages are remembered.
+const PUBLISHED_SIZE: usize = 100;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static CURRENT: RefCell<Option<Arc<Trace>>>;
+}
+
+/// Start tracing messages.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Is tracing enabled?
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// One hop of a message, from the source input to where it is now.
+#[derive(Debug)]
+pub struct Trace {
+    id: u64,
+    origin: Arc<str>,
+    received: SystemTime,
+    pipe: Option<PipeId>,
+    sent: SystemTime,
+    parent: Option<Arc<Trace>>,
+    span: tracing::Span,
+}
+
+impl Trace {
+    /// Get the id shared by every hop of the trace.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the topic of the MQTT message that started the trace.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Get the time the MQTT message that started the trace was received.
+    pub fn received(&self) -> SystemTime {
+        self.received
+    }
+
+    /// Get the pipe the message was sent to, or `None` for the start of the trace.
+    pub fn pipe(&self) -> Option<PipeId> {
+        self.pipe
+    }
+
+    /// Get the time the message was sent.
+    pub fn sent(&self) -> SystemTime {
+        self.sent
+    }
+
+    /// Get the previous hop.
+    pub fn parent(&self) -> Option<&Arc<Trace>> {
+        self.parent.as_ref()
+    }
+
+    /// Get the [tracing] span for this hop.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Iterate over the hops, starting with this one and ending with the source input.
+    pub fn hops(&self) -> impl Iterator<Item = &Trace> {
+        std::iter::successors(Some(self), |trace| trace.parent.as_deref())
+    }
+}
+
+/// A value and the trace of the message that caused it, as sent through a pipe.
+#[derive(Clone)]
+pub(crate) struct Envelope<T> {
+    pub(crate) value: T,
+    pub(crate) trace: Option<Arc<Trace>>,
+}
+
+/// Run a task with its own current trace.
+///
+/// Tasks created with [crate::spawn] and [crate::supervisor::supervise] already do this.
+pub async fn scope<F: Future>(future: F) -> F::Output {
+    CURRENT.scope(RefCell::new(None), future).await
+}
+
+/// Get the trace of the last message received by this task.
+pub fn current() -> Option<Arc<Trace>> {
+    CURRENT
+        .try_with(|current| current.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+pub(crate) fn set_current(trace: Option<Arc<Trace>>) {
+    let _ = CURRENT.try_with(|current| *current.borrow_mut() = trace);
+}
+
+/// Start a new trace for a message received on `origin`.
+///
+/// Messages this task sends afterwards carry the new trace.
+pub fn start(origin: &str) {
+    if !is_enabled() {
+        return;
+    }
+
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now();
+    let trace = Trace {
+        id,
+        origin: origin.into(),
+        received: now,
+        pipe: None,
+        sent: now,
+        parent: None,
+        span: tracing::info_span!("receive", trace_id = id, topic = origin),
+    };
+    set_current(Some(Arc::new(trace)));
+}
+
+/// Get the trace for a message this task sends to `pipe`.
+pub(crate) fn hop(pipe: PipeId) -> Option<Arc<Trace>> {
+    let parent = current()?;
+    let trace = Trace {
+        id: parent.id,
+        origin: parent.origin.clone(),
+        received: parent.received,
+        pipe: Some(pipe),
+        sent: SystemTime::now(),
+        span: tracing::debug_span!(parent: &parent.span, "hop", trace_id = parent.id, pipe = %pipe),
+        parent: Some(parent),
+    };
+    Some(Arc::new(trace))
+}
+
+/// A message published to MQTT.
+#[derive(Debug, Clone)]
+pub struct Published {
+    /// The topic of the message.
+    pub topic: String,
+    /// The payload of the message.
+    pub payload: String,
+    /// When the message was published.
+    pub published: SystemTime,
+    /// The trace of the message, if it was caused by a traced message.
+    pub trace: Option<Arc<Trace>>,
+}
+
+static PUBLISHED: Mutex<VecDeque<Published>> = Mutex::new(VecDeque::new());
+
+/// Remember that this task published a message, along with its current trace.
+pub(crate) fn record_published(topic: &str, payload: &str) {
+    if !is_enabled() {
+        return;
+    }
+
+    let trace = current();
+    if let Some(trace) = &trace {
+        tracing::info!(parent: &trace.span, topic, "published");
+    }
+
+    let mut published = PUBLISHED.lock().unwrap();
+    if published.len() >= PUBLISHED_SIZE {
+        published.pop_front();
+    }
+    published.push_back(Published {
+        topic: topic.to_string(),
+        payload: payload.to_string(),
+        published: SystemTime::now(),
+        trace,
+    });
+}
+
+/// Get the last messages published to MQTT, most recent first.
+pub fn published() -> Vec<Published> {
+    let published = PUBLISHED.lock().unwrap();
+    published.iter().rev().cloned().collect()
+}
+
+fn ago(time: SystemTime) -> String {
+    let elapsed = time.elapsed().unwrap_or_default();
+    format!("{:.3}s ago", elapsed.as_secs_f64())
+}
+
+/// Explain why the last message was published to `topic`.
+///
+/// Walks back from the published message to the MQTT message that caused it, listing
+/// every pipe on the way and the node that sent to it.
+pub fn why(topic: &str) -> Option<String> {
+    let published = published().into_iter().find(|p| p.topic == topic)?;
+
+    let mut text = String::new();
+    writeln!(text, "{} {}", published.topic, published.payload).unwrap();
+    writeln!(text, "published {}", ago(published.published)).unwrap();
+
+    let Some(trace) = &published.trace else {
+        writeln!(text, "not caused by a traced message").unwrap();
+        return Some(text);
+    };
+
+    writeln!(text, "trace {}", trace.id).unwrap();
+    for hop in trace.hops() {
+        if let Some(pipe) = hop.pipe {
+            let label = producer_label(pipe).unwrap_or_else(|| "unknown".to_string());
+            writeln!(text, "  {pipe} from {label}, sent {}", ago(hop.sent)).unwrap();
+        }
+    }
+    writeln!(text, "received {} {}", trace.origin, ago(trace.received)).unwrap();
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pipe;
+
+    #[tokio::test]
+    async fn test_trace_through_operators() {
+        enable();
+        let input = Pipe::new();
+        let output = input.to_rx_pipe().map(|v: i32| v * 2).filter(|v| *v > 2);
+        let mut rx = output.subscribe();
+        let tx = input.get_tx();
+
+        scope(async move {
+            start("test/trace");
+            tx.send(1).await.unwrap();
+            tx.send(2).await.unwrap();
+
+            assert_eq!(rx.recv().await.unwrap(), 4);
+            let trace = current().unwrap();
+            assert_eq!(trace.origin(), "test/trace");
+            let pipes: Vec<_> = trace.hops().filter_map(|hop| hop.pipe()).collect();
+            assert_eq!(pipes.len(), 3);
+            assert_eq!(pipes[0], output.id());
+            assert_eq!(pipes[2], input.id());
+            assert!(trace.hops().all(|hop| hop.id() == trace.id()));
+
+            record_published("test/why", "4");
+        })
+        .await;
+
+        let text = why("test/why").unwrap();
+        assert!(text.contains("from filter"));
+        assert!(text.contains("from map"));
+        assert!(text.contains("received test/trace"));
+        assert_eq!(why("test/nothing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_untraced() {
+        let (tx, mut rx) = crate::channel(10);
+        scope(async move {
+            tx.send(1).await.unwrap();
+            rx.recv().await.unwrap();
+            assert!(current().is_none());
+        })
+        .await;
+    }
+}
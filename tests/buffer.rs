@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use robotica_node_rust::{runtime, Pipe};
+use tokio::sync::broadcast::error::RecvError;
+
+#[tokio::test]
+async fn test_shutdown_sends_partial_buffers() {
+    let input = Pipe::new();
+    let tx = input.get_tx();
+    let rx_pipe = input.to_rx_pipe();
+    let mut by_time = rx_pipe.buffer_time(Duration::from_secs(60)).subscribe();
+    let mut by_count = rx_pipe.buffer_count(10).subscribe();
+    drop(input);
+
+    tx.send(1).await.unwrap();
+    tx.send(2).await.unwrap();
+
+    // Close the input long before the window ends.
+    runtime::shutdown();
+    drop(tx);
+
+    assert_eq!(by_time.recv().await.unwrap(), vec![1, 2]);
+    assert_eq!(by_count.recv().await.unwrap(), vec![1, 2]);
+    assert!(matches!(by_time.recv().await, Err(RecvError::Closed)));
+    assert!(matches!(by_count.recv().await, Err(RecvError::Closed)));
+}
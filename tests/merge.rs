@@ -0,0 +1,26 @@
+use robotica_node_rust::filters::generic::merge;
+use robotica_node_rust::{runtime, Pipe};
+use tokio::sync::broadcast::error::RecvError;
+
+#[tokio::test]
+async fn test_merge_closes_after_all_inputs() {
+    let a = Pipe::new();
+    let b = Pipe::new();
+    let mut rx = merge(vec![a.to_rx_pipe(), b.to_rx_pipe()]).subscribe();
+    let a_tx = a.get_tx();
+    let b_tx = b.get_tx();
+    drop(a);
+    drop(b);
+
+    // The merge task stops once its inputs are closed, which only happens during shutdown.
+    runtime::shutdown();
+
+    a_tx.send(1).await.unwrap();
+    drop(a_tx);
+    b_tx.send(2).await.unwrap();
+    assert_eq!(rx.recv().await.unwrap(), 1);
+    assert_eq!(rx.recv().await.unwrap(), 2);
+
+    drop(b_tx);
+    assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
+}
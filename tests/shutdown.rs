@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use robotica_node_rust::runtime;
+use robotica_node_rust::sources::timer::timer;
+use tokio::sync::broadcast::error::RecvError;
+
+#[tokio::test]
+async fn test_shutdown_drains_flows() {
+    let mut rx = timer(Duration::from_millis(10), 1)
+        .map(|v| v + 1)
+        .subscribe();
+
+    let v = rx.recv().await.unwrap();
+    assert_eq!(v, 2);
+
+    runtime::shutdown_and_wait(Duration::from_secs(5)).await;
+    assert_eq!(runtime::running_tasks(), 0);
+
+    loop {
+        match rx.recv().await {
+            Ok(v) => assert_eq!(v, 2),
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
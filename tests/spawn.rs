@@ -0,0 +1,13 @@
+use robotica_node_rust::{runtime, spawn};
+
+#[tokio::test]
+async fn test_spawn_returns_output() {
+    // Tasks that finish outside of shutdown exit the process.
+    runtime::shutdown();
+
+    assert_eq!(spawn(async { 42 }).await.unwrap(), 42);
+
+    let err = spawn(async { panic!("failed") }).await.unwrap_err();
+    assert!(err.is_cancelled());
+    assert_eq!(runtime::running_tasks(), 0);
+}
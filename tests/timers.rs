@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use robotica_node_rust::filters::timers::ThrottleEdge;
+use robotica_node_rust::{runtime, Pipe};
+use tokio::sync::broadcast::error::RecvError;
+
+#[tokio::test]
+async fn test_shutdown_sends_pending_values() {
+    let input = Pipe::new();
+    let tx = input.get_tx();
+    let rx_pipe = input.to_rx_pipe();
+    let minute = Duration::from_secs(60);
+    let mut outputs = [
+        rx_pipe.delay(minute).subscribe(),
+        rx_pipe.delay_when(|v| *v > 0, minute).subscribe(),
+        rx_pipe.debounce(minute).subscribe(),
+        rx_pipe.throttle(minute, ThrottleEdge::Trailing).subscribe(),
+    ];
+    drop(input);
+
+    tx.send(1).await.unwrap();
+
+    // Close the input long before the timers are due.
+    runtime::shutdown();
+    drop(tx);
+
+    for rx in &mut outputs {
+        assert_eq!(rx.recv().await.unwrap(), 1);
+        assert!(matches!(rx.recv().await, Err(RecvError::Closed)));
+    }
+}